@@ -0,0 +1,210 @@
+use std::fmt;
+
+use crate::fastq::*;
+use crate::samplesheet::*;
+
+/// Flags of unaligned reads: single-end, and R1 and R2 of a pair (paired, unmapped, mate
+/// unmapped, first or second in pair)
+pub const UNPAIRED: u16 = 0x4;
+pub const FIRST_OF_PAIR: u16 = 0x1 | 0x4 | 0x8 | 0x40;
+pub const SECOND_OF_PAIR: u16 = 0x1 | 0x4 | 0x8 | 0x80;
+
+/// Where a lane of reads was sequenced, the PU of its read group
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlatformUnit {
+    pub flowcell: String,
+    pub lane: u32,
+}
+
+impl PlatformUnit {
+    /// From a Casava 1.8 read name, `@<instrument>:<run>:<flowcell>:<lane>:<tile>:<x>:<y>`
+    pub fn from_header(header: &str) -> Option<PlatformUnit> {
+        let name = header.strip_prefix('@')?.split_whitespace().next()?;
+
+        match name.split(':').collect::<Vec<&str>>().as_slice() {
+            [_, _, flowcell, lane, _, _, _] => Some(PlatformUnit {
+                flowcell: flowcell.to_string(),
+                lane: lane.parse().ok()?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PlatformUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.flowcell, self.lane)
+    }
+}
+
+/// The read group ID of a sample's reads from one lane, unique across samples so their BAMs
+/// can be merged
+pub fn read_group_id(sample: &str, unit: &Option<PlatformUnit>) -> String {
+    match unit {
+        Some(unit) => format!("{}.{}", sample, unit),
+        None => sample.to_string(),
+    }
+}
+
+/// The library of a sample, from a Library or Library_ID column, or the sample ID
+pub fn sample_library(sample: &Sample) -> &str {
+    sample
+        .metadata
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("library") || k.eq_ignore_ascii_case("library_id"))
+        .map_or(&sample.id, |(_, v)| v)
+}
+
+/// The binary BAM header for one sample: no references, and a read group for every lane
+pub fn bam_header(sample: &str, library: &str, units: &[Option<PlatformUnit>]) -> Vec<u8> {
+    let mut text = String::from("@HD\tVN:1.6\tSO:unsorted\n");
+    for unit in units.iter() {
+        text.push_str(&format!(
+            "@RG\tID:{}\tSM:{}\tLB:{}\tPL:ILLUMINA",
+            read_group_id(sample, unit),
+            sample,
+            library
+        ));
+        if let Some(unit) = unit {
+            text.push_str(&format!("\tPU:{}", unit));
+        }
+        text.push('\n');
+    }
+    text.push_str(&format!(
+        "@PG\tID:deezmux\tPN:deezmux\tVN:{}\n",
+        env!("CARGO_PKG_VERSION")
+    ));
+
+    let mut out = Vec::with_capacity(text.len() + 12);
+    out.extend_from_slice(b"BAM\x01");
+    out.extend_from_slice(&(text.len() as i32).to_le_bytes());
+    out.extend_from_slice(text.as_bytes());
+    out.extend_from_slice(&0i32.to_le_bytes());
+    out
+}
+
+/// Appends an unaligned BAM record for `record`, tagged with its read group, the observed
+/// barcode (BC, `i7-i5`) and, when read from index files, the barcode qualities (QT)
+pub fn write_bam_record(
+    out: &mut Vec<u8>,
+    record: &FastqRecord,
+    flag: u16,
+    read_group: &str,
+    barcode: &str,
+    barcode_quality: &[u8],
+) {
+    let name = read_name(record.header);
+    let name = name.strip_prefix(b"@").unwrap_or(name);
+    let name = &name[..name.len().min(254)];
+
+    let start = out.len();
+    out.extend_from_slice(&[0; 4]);
+
+    out.extend_from_slice(&(-1i32).to_le_bytes()); // refID
+    out.extend_from_slice(&(-1i32).to_le_bytes()); // pos
+    out.push(name.len() as u8 + 1);
+    out.push(255); // MAPQ
+    out.extend_from_slice(&4680u16.to_le_bytes()); // bin of an unplaced read
+    out.extend_from_slice(&0u16.to_le_bytes()); // CIGAR operations
+    out.extend_from_slice(&flag.to_le_bytes());
+    out.extend_from_slice(&(record.seq.len() as i32).to_le_bytes());
+    out.extend_from_slice(&(-1i32).to_le_bytes()); // next refID
+    out.extend_from_slice(&(-1i32).to_le_bytes()); // next pos
+    out.extend_from_slice(&0i32.to_le_bytes()); // template length
+
+    out.extend_from_slice(name);
+    out.push(0);
+
+    // Two bases per byte, first base in the high nibble
+    for bases in record.seq.chunks(2) {
+        let high = base_code(bases[0]) << 4;
+        let low = bases.get(1).map_or(0, |x| base_code(*x));
+        out.push(high | low);
+    }
+    out.extend(record.qual.iter().map(|x| x.saturating_sub(33)));
+
+    let mut tag = |name: &[u8], value: &[u8]| {
+        out.extend_from_slice(name);
+        out.push(b'Z');
+        out.extend_from_slice(value);
+        out.push(0);
+    };
+    tag(b"RG", read_group.as_bytes());
+    if !barcode.is_empty() {
+        tag(b"BC", barcode.replace('+', "-").as_bytes());
+    }
+    if !barcode_quality.is_empty() {
+        tag(b"QT", barcode_quality);
+    }
+
+    let block_size = (out.len() - start - 4) as i32;
+    out[start..start + 4].copy_from_slice(&block_size.to_le_bytes());
+}
+
+/// The 4-bit code of a base in `=ACMGRSVTWYHKDBN`, N for anything else
+fn base_code(base: u8) -> u8 {
+    match base.to_ascii_uppercase() {
+        b'=' => 0,
+        b'A' => 1,
+        b'C' => 2,
+        b'M' => 3,
+        b'G' => 4,
+        b'R' => 5,
+        b'S' => 6,
+        b'V' => 7,
+        b'T' => 8,
+        b'W' => 9,
+        b'Y' => 10,
+        b'H' => 11,
+        b'K' => 12,
+        b'D' => 13,
+        b'B' => 14,
+        _ => 15,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn platform_unit_from_header() {
+        assert_eq!(
+            PlatformUnit::from_header(
+                "@MT_E00516:746:HG3WYCCX2:6:1101:2229:1661 1:N:0:NGAGCTAG+NAGCCTGA"
+            ),
+            Some(PlatformUnit {
+                flowcell: "HG3WYCCX2".to_string(),
+                lane: 6
+            })
+        );
+        assert_eq!(PlatformUnit::from_header("@read1"), None);
+        assert_eq!(
+            read_group_id("S1", &PlatformUnit::from_header("@A:1:FC:2:1:1:1")),
+            "S1.FC.2"
+        );
+    }
+
+    #[test]
+    fn encodes_unaligned_record() {
+        let record = FastqRecord {
+            header: b"@r1/1",
+            seq: b"ACGTN",
+            plus: b"+",
+            qual: b"I#III",
+        };
+        let mut out = Vec::new();
+        write_bam_record(&mut out, &record, FIRST_OF_PAIR, "S1", "AC+GT", b"II II");
+
+        let block_size = i32::from_le_bytes(out[..4].try_into().unwrap()) as usize;
+        assert_eq!(block_size, out.len() - 4);
+        assert_eq!(u16::from_le_bytes([out[18], out[19]]), 77);
+        assert_eq!(i32::from_le_bytes(out[20..24].try_into().unwrap()), 5);
+
+        // Name, then packed bases and qualities
+        assert_eq!(&out[36..39], b"r1\0");
+        assert_eq!(&out[39..42], &[0x12, 0x48, 0xf0]);
+        assert_eq!(&out[42..47], &[40, 2, 40, 40, 40]);
+        assert_eq!(&out[47..], b"RGZS1\0BCZAC-GT\0QTZII II\0".as_slice());
+    }
+}
@@ -0,0 +1,212 @@
+use crossbeam::channel::{bounded, Sender};
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use libdeflater::Decompressor;
+use std::io::{self, ErrorKind, Read, Write};
+use std::thread;
+
+use crate::compression::*;
+
+/// Most bytes of input in one block. htslib uses the same limit so that even incompressible
+/// data fits the 64 KiB block size.
+pub const BLOCK_SIZE: usize = 0xff00;
+
+/// Most bytes a BGZF block can inflate to
+const MAX_BLOCK_OUTPUT: usize = 1 << 16;
+
+/// BGZF blocks a worker inflates in one go
+const BLOCKS_PER_JOB: usize = 64;
+
+/// The empty block that marks the end of a BGZF file
+pub const EOF_BLOCK: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Compresses `data` into BGZF blocks: independent gzip members of at most `BLOCK_SIZE`
+/// bytes each, with the BC extra field giving the block size. Any gzip reader sees them as
+/// one stream, and blocks compressed separately can simply be concatenated.
+pub fn compress_blocks(data: &[u8], level: Compression) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 2);
+
+    for block in data.chunks(BLOCK_SIZE) {
+        let mut encoder = DeflateEncoder::new(Vec::with_capacity(block.len()), level);
+        encoder
+            .write_all(block)
+            .expect("Writing to a Vec cannot fail");
+        let deflated = encoder.finish().expect("Writing to a Vec cannot fail");
+
+        let mut crc = Crc::new();
+        crc.update(block);
+
+        // Header (18 bytes) + deflated data + CRC32 and input size (8 bytes), less one
+        let block_size = (deflated.len() + 25) as u16;
+
+        out.extend_from_slice(&[
+            0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0,
+        ]);
+        out.extend_from_slice(&block_size.to_le_bytes());
+        out.extend_from_slice(&deflated);
+        out.extend_from_slice(&crc.sum().to_le_bytes());
+        out.extend_from_slice(&(block.len() as u32).to_le_bytes());
+    }
+
+    out
+}
+
+/// True if the file starts with a gzip header carrying the BGZF `BC` extra field
+pub fn is_bgzf(header: &[u8]) -> bool {
+    header.len() >= 18
+        && header[..4] == [0x1f, 0x8b, 0x08, 0x04]
+        && header[12..16] == [b'B', b'C', 2, 0]
+}
+
+/// Splits the file into BGZF blocks and hands them to the workers in groups
+pub fn read_blocks<R: Read>(
+    mut reader: R,
+    threads: usize,
+    sender: &Sender<Chunk>,
+) -> io::Result<()> {
+    let (jobs, pool) = bounded::<(Vec<Vec<u8>>, Sender<io::Result<Vec<u8>>>)>(threads);
+    for _ in 0..threads {
+        let pool = pool.clone();
+        thread::spawn(move || {
+            let mut decompressor = Decompressor::new();
+            for (blocks, result) in pool.iter() {
+                let _ = result.send(inflate_blocks(&mut decompressor, &blocks));
+            }
+        });
+    }
+
+    let mut blocks = Vec::with_capacity(BLOCKS_PER_JOB);
+    loop {
+        let block = next_block(&mut reader)?;
+        let done = block.is_none();
+        blocks.extend(block);
+
+        if blocks.len() == BLOCKS_PER_JOB || (done && !blocks.is_empty()) {
+            let (send, receive) = bounded(1);
+            jobs.send((std::mem::take(&mut blocks), send))
+                .expect("Decompression workers stopped");
+            if sender.send(receive).is_err() {
+                return Ok(());
+            }
+        }
+
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+/// The next whole BGZF block, or None at the end of the file
+fn next_block<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let invalid = |message: &str| io::Error::new(ErrorKind::InvalidData, message.to_string());
+    let truncated = |e: io::Error| match e.kind() {
+        ErrorKind::UnexpectedEof => invalid("Truncated BGZF block"),
+        _ => e,
+    };
+
+    let mut block = vec![0; 12];
+    match reader.read(&mut block[..1])? {
+        0 => return Ok(None),
+        _ => reader.read_exact(&mut block[1..]).map_err(truncated)?,
+    }
+    if block[..4] != [0x1f, 0x8b, 0x08, 0x04] {
+        return Err(invalid("Expected a BGZF block"));
+    }
+
+    let extra_len = u16::from_le_bytes([block[10], block[11]]) as usize;
+    block.resize(12 + extra_len, 0);
+    reader.read_exact(&mut block[12..]).map_err(truncated)?;
+
+    // The BC subfield holds the size of the whole block, less one
+    let mut extra = &block[12..];
+    let mut block_size = None;
+    while extra.len() >= 4 {
+        let len = u16::from_le_bytes([extra[2], extra[3]]) as usize;
+        if extra[..2] == [b'B', b'C'] && len == 2 && extra.len() >= 6 {
+            block_size = Some(u16::from_le_bytes([extra[4], extra[5]]) as usize + 1);
+        }
+        extra = &extra[(4 + len).min(extra.len())..];
+    }
+
+    let block_size = match block_size {
+        Some(x) if x >= block.len() + 8 => x,
+        _ => return Err(invalid("Expected a BGZF block")),
+    };
+    let start = block.len();
+    block.resize(block_size, 0);
+    reader.read_exact(&mut block[start..]).map_err(truncated)?;
+
+    Ok(Some(block))
+}
+
+/// Inflates consecutive BGZF blocks into one buffer, using the sizes in their trailers
+fn inflate_blocks(decompressor: &mut Decompressor, blocks: &[Vec<u8>]) -> io::Result<Vec<u8>> {
+    let size = |block: &Vec<u8>| {
+        let n = block.len();
+        u32::from_le_bytes([block[n - 4], block[n - 3], block[n - 2], block[n - 1]]) as usize
+    };
+
+    // A corrupt trailer must not allocate gigabytes
+    if blocks.iter().any(|x| size(x) > MAX_BLOCK_OUTPUT) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "BGZF block larger than 64 KiB",
+        ));
+    }
+
+    let mut out = vec![0; blocks.iter().map(size).sum()];
+    let mut position = 0;
+    for block in blocks.iter() {
+        let end = position + size(block);
+        decompressor
+            .gzip_decompress(block, &mut out[position..end])
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        position = end;
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::bufread::MultiGzDecoder;
+
+    #[test]
+    fn blocks_read_as_one_gzip_stream() {
+        let data: Vec<u8> = (0..200_000).map(|x| b"ACGT\n"[x % 5]).collect();
+
+        let mut compressed = compress_blocks(&data[..100_000], Compression::fast());
+        compressed.extend(compress_blocks(&data[100_000..], Compression::fast()));
+        compressed.extend_from_slice(&EOF_BLOCK);
+        assert!(is_bgzf(&compressed));
+
+        let mut out = Vec::new();
+        MultiGzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, data);
+
+        // Each block records its own size, less one
+        let block_size = u16::from_le_bytes([compressed[16], compressed[17]]) as usize + 1;
+        assert_eq!(&compressed[block_size..block_size + 2], &[0x1f, 0x8b]);
+
+        // Inflated again on several threads
+        let mut out = Vec::new();
+        DecompressReader::new(io::Cursor::new(compressed.clone()), 3)
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, data);
+
+        // A trailer claiming 4 GiB is an error, not an allocation
+        let mut corrupt = compressed[..block_size].to_vec();
+        corrupt[block_size - 4..].copy_from_slice(&u32::MAX.to_le_bytes());
+        let error = DecompressReader::new(io::Cursor::new(corrupt), 1)
+            .read_to_end(&mut out)
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,277 @@
+use bzip2::bufread::MultiBzDecoder;
+use clap::ArgEnum;
+use crossbeam::channel::{bounded, Receiver, Sender};
+use flate2::Compression;
+use std::io::{self, BufReader, Read};
+use std::ops::RangeInclusive;
+use std::thread;
+
+use crate::bgzf::*;
+use crate::gzip::*;
+
+/// Bytes a compressed stream is decompressed in at a time
+const STREAM_CHUNK_SIZE: usize = 1 << 20;
+
+/// Bytes `InputFormat::detect` needs to tell every format apart, a BGZF header
+const DETECT_SIZE: u64 = 18;
+
+/// How an input file is compressed, going by its first bytes rather than its name
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputFormat {
+    /// Blocked gzip, which can be decompressed in parallel
+    Bgzf,
+    Gzip,
+    Zstd,
+    Bzip2,
+    Plain,
+}
+
+impl InputFormat {
+    pub fn detect(header: &[u8]) -> InputFormat {
+        if is_bgzf(header) {
+            InputFormat::Bgzf
+        } else if header.starts_with(&[0x1f, 0x8b]) {
+            InputFormat::Gzip
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            InputFormat::Zstd
+        } else if header.starts_with(b"BZh") {
+            InputFormat::Bzip2
+        } else {
+            InputFormat::Plain
+        }
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// BGZF, which any gzip reader can read
+    Gz,
+    /// Zstandard
+    Zst,
+    /// Uncompressed FASTQ
+    None,
+    /// Unaligned BAM, both mates of a pair in one file
+    Bam,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Gz => ".fq.gz",
+            OutputFormat::Zst => ".fq.zst",
+            OutputFormat::None => ".fq",
+            OutputFormat::Bam => ".bam",
+        }
+    }
+
+    /// The level used when none is given: fast for gzip and BAM, zstd's own default
+    pub fn default_level(&self) -> i32 {
+        match self {
+            OutputFormat::Gz | OutputFormat::Bam => 1,
+            OutputFormat::Zst => 3,
+            OutputFormat::None => 0,
+        }
+    }
+
+    /// Levels the format accepts, any level is ignored for uncompressed output
+    pub fn levels(&self) -> RangeInclusive<i32> {
+        match self {
+            OutputFormat::Gz | OutputFormat::Bam => 0..=9,
+            OutputFormat::Zst => zstd::compression_level_range(),
+            OutputFormat::None => i32::MIN..=i32::MAX,
+        }
+    }
+
+    /// Compresses a batch on its own (BGZF blocks or a zstd frame), so batches compressed on
+    /// different threads can be written one after the other
+    pub fn compress(&self, data: &[u8], level: i32) -> Vec<u8> {
+        match self {
+            OutputFormat::Gz | OutputFormat::Bam => {
+                compress_blocks(data, Compression::new(level as u32))
+            }
+            OutputFormat::Zst => {
+                zstd::bulk::compress(data, level).expect("Compressing to memory cannot fail")
+            }
+            OutputFormat::None => data.to_vec(),
+        }
+    }
+
+    /// Written after the last batch
+    pub fn trailer(&self) -> &'static [u8] {
+        match self {
+            OutputFormat::Gz | OutputFormat::Bam => &EOF_BLOCK,
+            _ => &[],
+        }
+    }
+}
+
+/// Decompressed data, in the order it appears in the file, once a worker is done with it
+pub type Chunk = Receiver<io::Result<Vec<u8>>>;
+
+/// Decompresses a file ahead of whoever reads it, whatever `InputFormat` it is in. BGZF files
+/// (bcl2fastq output, or ours) are split into their blocks, and other gzip files into their
+/// members, which `threads` workers inflate with libdeflate. Anything else is decompressed as
+/// a stream on a thread of its own.
+pub struct DecompressReader {
+    chunks: Receiver<Chunk>,
+    current: Vec<u8>,
+    position: usize,
+}
+
+impl DecompressReader {
+    pub fn new<R: Read + Send + 'static>(reader: R, threads: usize) -> DecompressReader {
+        let threads = threads.max(1);
+        let (sender, chunks) = bounded(threads * 2);
+
+        thread::spawn(move || {
+            // A pipe can hand over fewer bytes than that at a time, so read until there are
+            // enough or the input ends
+            let mut reader = reader;
+            let mut header = Vec::new();
+            let format = (&mut reader)
+                .take(DETECT_SIZE)
+                .read_to_end(&mut header)
+                .map(|_| InputFormat::detect(&header));
+            let reader = BufReader::with_capacity(1 << 20, io::Cursor::new(header).chain(reader));
+
+            let result = match format {
+                Ok(InputFormat::Bgzf) => read_blocks(reader, threads, &sender),
+                Ok(InputFormat::Gzip) => read_members(reader, threads, &sender),
+                Ok(InputFormat::Zstd) => zstd::stream::read::Decoder::with_buffer(reader)
+                    .and_then(|x| read_stream(x, &sender)),
+                Ok(InputFormat::Bzip2) => read_stream(MultiBzDecoder::new(reader), &sender),
+                Ok(InputFormat::Plain) => read_stream(reader, &sender),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                send_chunk(&sender, Err(e));
+            }
+        });
+
+        DecompressReader {
+            chunks,
+            current: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl Read for DecompressReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.current.len() {
+            let chunk = match self.chunks.recv() {
+                Ok(chunk) => chunk,
+                // Every chunk has been read
+                Err(_) => return Ok(0),
+            };
+            self.current = chunk
+                .recv()
+                .map_err(|_| io::Error::other("Decompression worker stopped"))??;
+            self.position = 0;
+        }
+
+        let n = buf.len().min(self.current.len() - self.position);
+        buf[..n].copy_from_slice(&self.current[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// Queues data that is already decompressed. False once the reader has gone away.
+pub fn send_chunk(sender: &Sender<Chunk>, data: io::Result<Vec<u8>>) -> bool {
+    let (send, receive) = bounded(1);
+    let _ = send.send(data);
+    sender.send(receive).is_ok()
+}
+
+/// Passes on a decompressing stream in chunks
+pub fn read_stream<R: Read>(mut decoder: R, sender: &Sender<Chunk>) -> io::Result<()> {
+    loop {
+        let mut chunk = Vec::with_capacity(STREAM_CHUNK_SIZE);
+        (&mut decoder)
+            .take(STREAM_CHUNK_SIZE as u64)
+            .read_to_end(&mut chunk)?;
+
+        if chunk.is_empty() || !send_chunk(sender, Ok(chunk)) {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const FASTQ: &[u8] = b"@a\nACGT\n+\nIIII\n";
+
+    fn decompress(data: Vec<u8>) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        DecompressReader::new(io::Cursor::new(data), 2).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn reads_every_input_format() {
+        let data = FASTQ.repeat(1000);
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), Compression::fast());
+        gzip.write_all(&data).unwrap();
+        let gzip = gzip.finish().unwrap();
+
+        let mut bzip2 = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+        bzip2.write_all(&data).unwrap();
+        let bzip2 = bzip2.finish().unwrap();
+
+        let zstd = zstd::encode_all(data.as_slice(), 3).unwrap();
+
+        assert_eq!(InputFormat::detect(&gzip), InputFormat::Gzip);
+        assert_eq!(InputFormat::detect(&bzip2), InputFormat::Bzip2);
+        assert_eq!(InputFormat::detect(&zstd), InputFormat::Zstd);
+        assert_eq!(InputFormat::detect(&data), InputFormat::Plain);
+
+        for compressed in [gzip, bzip2, zstd, data.clone()] {
+            assert_eq!(decompress(compressed).unwrap(), data);
+        }
+
+        // Looks like gzip, but isn't
+        assert!(decompress(vec![0x1f, 0x8b, 0, 0]).is_err());
+    }
+
+    /// Hands over one byte per read, like a slow pipe
+    struct Trickle(io::Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(1);
+            self.0.read(&mut buf[..n])
+        }
+    }
+
+    #[test]
+    fn detects_format_from_short_reads() {
+        let data = FASTQ.repeat(100);
+        let zstd = zstd::encode_all(data.as_slice(), 3).unwrap();
+        let bgzf = OutputFormat::Gz.compress(&data, 1);
+
+        for compressed in [zstd, bgzf, data.clone()] {
+            let mut out = Vec::new();
+            DecompressReader::new(Trickle(io::Cursor::new(compressed)), 2)
+                .read_to_end(&mut out)
+                .unwrap();
+            assert_eq!(out, data);
+        }
+    }
+
+    #[test]
+    fn output_batches_concatenate() {
+        for format in [OutputFormat::Gz, OutputFormat::Zst, OutputFormat::None] {
+            let level = format.default_level();
+            let mut out = format.compress(FASTQ, level);
+            out.extend(format.compress(FASTQ, level));
+            out.extend_from_slice(format.trailer());
+
+            assert_eq!(decompress(out).unwrap(), FASTQ.repeat(2), "{:?}", format);
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum DeezmuxError {
+    /// The barcode file or sample sheet could not be used
+    SampleSheet(String),
+    /// The read files could not be found, or don't make sense together
+    InputDiscovery(String),
+    /// Options that don't make sense
+    Arguments(String),
+    /// Malformed FASTQ, with the 1-based record number where it was found
+    FastqFormat {
+        file: String,
+        record: usize,
+        message: String,
+    },
+    Io {
+        context: String,
+        source: io::Error,
+    },
+}
+
+impl DeezmuxError {
+    /// For `map_err`, wraps an I/O error with what was being done when it happened
+    pub fn io(context: impl Into<String>) -> impl FnOnce(io::Error) -> DeezmuxError {
+        let context = context.into();
+        move |source| DeezmuxError::Io { context, source }
+    }
+
+    pub fn fastq(file: &str, record: usize, message: impl Into<String>) -> DeezmuxError {
+        DeezmuxError::FastqFormat {
+            file: file.to_string(),
+            record,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for DeezmuxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeezmuxError::SampleSheet(message) => write!(f, "Barcode file: {}", message),
+            DeezmuxError::InputDiscovery(message) => write!(f, "Input files: {}", message),
+            DeezmuxError::Arguments(message) => write!(f, "Arguments: {}", message),
+            DeezmuxError::FastqFormat {
+                file,
+                record,
+                message,
+            } => write!(
+                f,
+                "Invalid FASTQ in {} at record {}: {}",
+                file, record, message
+            ),
+            DeezmuxError::Io { context, source } => write!(f, "{}: {}", context, source),
+        }
+    }
+}
+
+impl std::error::Error for DeezmuxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DeezmuxError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
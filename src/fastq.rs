@@ -1,33 +1,116 @@
-use bytelines::*;
 use crossbeam::channel::{bounded, Receiver, Sender};
 use crossbeam::thread;
-use flate2::read::MultiGzDecoder;
-use flate2::write::GzEncoder;
-use flate2::Compression;
 use hashbrown::HashMap;
+use memchr::memchr;
 use simdutf8::basic::from_utf8;
-use triple_accel::*;
 use twox_hash::xxh3::RandomHashBuilder64;
 
 // use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::fs;
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::path::PathBuf;
+use std::io::{self, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 // use std::thread;
 
-/* pub struct FastqEntry<'fq> {
-    pub id: &'fq [u8],
-    pub scores: &'fq [u8],
-    pub sequence: &'fq [u8],
-} */
+use crate::bam::*;
+use crate::compression::*;
+use crate::error::DeezmuxError;
+use crate::matcher::*;
+use crate::samplesheet::*;
+use crate::stats::*;
 
+/// Records parsed by a reader thread before they are handed on
+const BATCH_SIZE: usize = 4096;
+
+/// Bytes read from the input at a time
+const READ_SIZE: usize = 1 << 18;
+
+/// Bytes of R1 text an output collects before it is handed to its writer. Larger batches
+/// mean fewer channel sends and wake-ups, at the cost of memory per sample.
+const OUTPUT_BATCH_SIZE: usize = 1 << 18;
+
+/// FASTQ text for one output's R1 and R2 files (R2 is empty for single-end reads)
+type OutputBatch = (Vec<u8>, Vec<u8>);
+
+/// Text for a compression worker, and where to send it back compressed
+type CompressJob = (Vec<u8>, Sender<Vec<u8>>);
+
+/// A FASTQ record borrowed from a `RecordBatch`, each line without its line ending
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FastqRecord<'fq> {
+    pub header: &'fq [u8],
+    pub seq: &'fq [u8],
+    pub plus: &'fq [u8],
+    pub qual: &'fq [u8],
+}
+
+impl<'fq> FastqRecord<'fq> {
+    fn new(data: &'fq [u8], lines: &[(usize, usize); 4]) -> FastqRecord<'fq> {
+        let line = |i: usize| &data[lines[i].0..lines[i].1];
+        FastqRecord {
+            header: line(0),
+            seq: line(1),
+            plus: line(2),
+            qual: line(3),
+        }
+    }
+
+    /// Appends the record to `out` as four lines
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        for line in [self.header, self.seq, self.plus, self.qual] {
+            out.extend_from_slice(line);
+            out.push(b'\n');
+        }
+    }
+}
+
+/// Consecutive records of one file, parsed in place in the buffer they were read into
+pub struct RecordBatch {
+    data: Arc<Vec<u8>>,
+    /// Start and end of the four lines of every record in `data`
+    lines: Vec<[(usize, usize); 4]>,
+    /// 1-based number of the first record in the file
+    first: usize,
+    /// Name of the file the records are from, for errors
+    file: Arc<str>,
+}
+
+impl RecordBatch {
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    pub fn get(&self, i: usize) -> FastqRecord<'_> {
+        FastqRecord::new(&self.data, &self.lines[i])
+    }
+
+    /// Splits a batch of interleaved records into its R1 and R2 records, numbered by pair.
+    /// Both halves share the buffer.
+    fn deinterleave(self) -> (RecordBatch, RecordBatch) {
+        let mate = |offset: usize| RecordBatch {
+            data: Arc::clone(&self.data),
+            lines: self.lines.iter().skip(offset).step_by(2).copied().collect(),
+            first: self.first.div_ceil(2),
+            file: Arc::clone(&self.file),
+        };
+        (mate(0), mate(1))
+    }
+}
+
+/// Scans barcodes without splitting the reads
 pub struct FastqSplitter {
     // files: Vec<String>,
     mm1: u32,
     mm2: u32,
-    barcodes: Vec<(String, String, String, String, String)>,
+    mode: MatchMode,
+    barcodes: SampleSheet,
+    interleaved: bool,
     // basename: String,
 }
 
@@ -39,7 +122,9 @@ impl FastqSplitter {
             // files: Vec::new(),
             mm1: 2,
             mm2: 2,
-            barcodes: Vec::new(),
+            mode: MatchMode::Levenshtein,
+            barcodes: SampleSheet::new(),
+            interleaved: false,
             // basename: "output".to_string(),
         }
     }
@@ -56,378 +141,916 @@ impl FastqSplitter {
         self
     }
 
-    pub fn with_barcodes(
-        mut self,
-        barcodes: Vec<(String, String, String, String, String)>,
-    ) -> FastqSplitter {
+    pub fn with_match_mode(mut self, mode: MatchMode) -> FastqSplitter {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_barcodes(mut self, barcodes: SampleSheet) -> FastqSplitter {
         self.barcodes = barcodes;
         self
     }
 
+    /// The reads alternate between R1 and R2 records, only R1 headers are counted
+    pub fn with_interleaved(mut self, interleaved: bool) -> FastqSplitter {
+        self.interleaved = interleaved;
+        self
+    }
+
     /*     pub fn with_basename(mut self, basename: String) -> FastqSplitter {
         self.basename = basename;
         self twox_hash::xxh3::RandomHashBuilder64
     } */
 
-    pub fn match_barcodes<R: Read + Send + Sync>(
+    /// Counts the barcodes in the Casava 1.8 headers of `reader` and assigns them, without
+    /// writing any reads
+    pub fn match_header_barcodes(&self, r1: InputFiles) -> Result<DemuxStats, DeezmuxError> {
+        thread::scope(|s| {
+            let receivers = match self.interleaved {
+                true => {
+                    let (r1, r2) = spawn_interleaved_reader(s, r1);
+                    vec![r1, r2]
+                }
+                false => vec![spawn_record_reader(s, r1)],
+            };
+            self.match_barcodes(&receivers, false)
+        })
+        .expect("Unable to properly scope")
+    }
+
+    /// Counts the barcodes in the I1 (and I2) files and assigns them, without writing any reads
+    pub fn match_index_barcodes(
         &self,
-        reader: R,
-    ) -> HashMap<String, String, RandomHashBuilder64> {
-        let (sender, receiver) = bounded(100000);
+        i1: InputFiles,
+        i2: Option<InputFiles>,
+    ) -> Result<DemuxStats, DeezmuxError> {
+        thread::scope(|s| {
+            let mut receivers = vec![spawn_record_reader(s, i1)];
+            if let Some(i2) = i2 {
+                receivers.push(spawn_record_reader(s, i2));
+            }
+            self.match_barcodes(&receivers, true)
+        })
+        .expect("Unable to properly scope")
+    }
 
-        let mut assigned_barcodes: HashMap<String, String, RandomHashBuilder64> =
-            Default::default();
+    /// Counts every barcode while the reader threads parse, then assigns each distinct
+    /// barcode once
+    fn match_barcodes(
+        &self,
+        receivers: &[Records],
+        from_index: bool,
+    ) -> Result<DemuxStats, DeezmuxError> {
         let mut counts: HashMap<String, usize, RandomHashBuilder64> = Default::default();
+        let mut barcode = String::new();
 
-        thread::scope(|s| {
-            let mut lines = BufReader::new(reader).byte_lines();
+        while let Some(batches) = next_batches(receivers)? {
+            for i in 0..batches[0].len() {
+                record_barcode(&batches, from_index, i, &mut barcode)?;
+                match counts.get_mut(barcode.as_str()) {
+                    Some(count) => *count += 1,
+                    None => {
+                        counts.insert(barcode.clone(), 1);
+                    }
+                }
+            }
+        }
 
-            s.spawn(move |_| {
-                let mut header;
-                let mut id;
-
-                loop {
-                    match lines.next() {
-                        Some(Ok(line)) => {
-                            header = from_utf8(line).expect("FASTQ Header line is not valid UTF-8");
-                            let n = header.len();
-                            id = header[n - 17..n].to_string();
-                            sender.send(Some(id)).expect("Error sending");
-                        }
-                        _ => {
-                            sender.send(None).expect("Error sending complete");
-                            break;
-                        }
-                    };
+        let matcher = BarcodeMatcher::new(self.mode, self.barcodes.clone(), (self.mm1, self.mm2));
+
+        Ok(DemuxStats::new(
+            &matcher,
+            counts
+                .iter()
+                .map(|(barcode, count)| (barcode.as_str(), matcher.assign(barcode), *count)),
+        ))
+    }
+}
+
+/// An opened input file
+pub type InputReader = Box<dyn Read + Send + Sync>;
+
+/// The opened files of one read, each with the name errors refer to it by. They are read
+/// one after the other, record numbers starting again in each.
+pub type InputFiles = Vec<(String, InputReader)>;
+
+/// True for `-`, which stands for standard input
+pub fn is_stdin(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// How an input file is named in errors
+pub fn input_name(path: &Path) -> String {
+    match is_stdin(path) {
+        true => "standard input".to_string(),
+        false => path.display().to_string(),
+    }
+}
+
+/// Opens a FASTQ file (or standard input for `-`) in any `InputFormat`, decompressed ahead
+/// on `threads` threads when it is BGZF
+pub fn open_input(path: &Path, threads: usize) -> Result<DecompressReader, DeezmuxError> {
+    if is_stdin(path) {
+        return Ok(DecompressReader::new(std::io::stdin(), threads));
+    }
+
+    let fh = File::open(path).map_err(DeezmuxError::io(format!(
+        "Unable to open {}",
+        path.display()
+    )))?;
+    Ok(DecompressReader::new(fh, threads))
+}
 
-                    lines
-                        .next()
-                        .expect("Invalid FASTQ File")
-                        .expect("Invalid FASTQ File");
-                    lines
-                        .next()
-                        .expect("Invalid FASTQ File")
-                        .expect("Invalid FASTQ File");
-                    lines
-                        .next()
-                        .expect("Invalid FASTQ File")
-                        .expect("Invalid FASTQ File");
+/// Opens the FASTQ files of one read
+pub fn open_input_files(paths: &[PathBuf], threads: usize) -> Result<InputFiles, DeezmuxError> {
+    let mut files = InputFiles::with_capacity(paths.len());
+    for path in paths.iter() {
+        files.push((input_name(path), Box::new(open_input(path, threads)?)));
+    }
+    Ok(files)
+}
+
+/// Finds the four lines of the record starting at `start`, returning them with the start of
+/// the next record. None if `data` ends first; a last line without a newline only counts
+/// once the file has ended.
+fn find_record(data: &[u8], start: usize, eof: bool) -> Option<([(usize, usize); 4], usize)> {
+    let mut lines = [(0, 0); 4];
+    let mut position = start;
+
+    for line in lines.iter_mut() {
+        let end = match memchr(b'\n', &data[position..]) {
+            Some(i) => position + i,
+            None if eof && position < data.len() => data.len(),
+            None => return None,
+        };
+        let trimmed = match end > position && data[end - 1] == b'\r' {
+            true => end - 1,
+            false => end,
+        };
+        *line = (position, trimmed);
+        position = (end + 1).min(data.len());
+    }
+
+    Some((lines, position))
+}
+
+/// Reads FASTQ records in batches, checking their structure and keeping track of the file
+/// and record number for errors. Files are read one after the other, and a batch never
+/// holds records of two files.
+struct RecordReader<R: Read> {
+    reader: R,
+    /// The start of a record that did not fit in the previous batch
+    pending: Vec<u8>,
+    eof: bool,
+    file: Arc<str>,
+    record: usize,
+    /// Records in a full batch
+    batch_size: usize,
+    /// Files still to be read once this one has ended
+    next_files: VecDeque<(String, R)>,
+}
+
+impl<R: Read> RecordReader<R> {
+    fn new(files: Vec<(String, R)>) -> RecordReader<R> {
+        let mut next_files = VecDeque::from(files);
+        let (file, reader) = next_files.pop_front().expect("No input files");
+
+        RecordReader {
+            reader,
+            pending: Vec::new(),
+            eof: false,
+            file: file.into(),
+            record: 0,
+            batch_size: BATCH_SIZE,
+            next_files,
+        }
+    }
+
+    fn error(&self, record: usize, message: impl Into<String>) -> DeezmuxError {
+        DeezmuxError::fastq(&self.file, record, message)
+    }
+
+    /// Appends the next read from the input to `data`
+    fn fill(&mut self, data: &mut Vec<u8>) -> Result<(), DeezmuxError> {
+        let len = data.len();
+        data.resize(len + READ_SIZE, 0);
+
+        loop {
+            match self.reader.read(&mut data[len..]) {
+                Ok(n) => {
+                    data.truncate(len + n);
+                    self.eof = n == 0;
+                    return Ok(());
                 }
-            });
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(DeezmuxError::io(format!("Unable to read {}", self.file))(e)),
+            }
+        }
+    }
 
-            while let Ok(Some(id)) = receiver.recv() {
-                let count = match counts.get_mut(&id) {
-                    Some(count) => count,
-                    None => {
-                        counts.insert(id.clone(), 0);
-                        counts.get_mut(&id).unwrap()
-                    }
-                };
-                *count += 1;
+    fn check(&self, record: &FastqRecord, number: usize) -> Result<(), DeezmuxError> {
+        if !record.header.starts_with(b"@") {
+            return Err(self.error(number, "Header does not start with @"));
+        }
+        if !record.plus.starts_with(b"+") {
+            return Err(self.error(number, "Separator line does not start with +"));
+        }
+        if record.seq.len() != record.qual.len() {
+            return Err(self.error(
+                number,
+                format!(
+                    "Sequence and quality have different lengths ({} and {})",
+                    record.seq.len(),
+                    record.qual.len()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Up to `batch_size` records, or None once every file has ended
+    fn next_batch(&mut self) -> Result<Option<RecordBatch>, DeezmuxError> {
+        loop {
+            if let Some(batch) = self.file_batch()? {
+                return Ok(Some(batch));
             }
-        })
-        .unwrap();
 
-        let mut hash_vec: Vec<(&String, &usize)> = counts.iter().collect();
-        hash_vec.sort_by(|a, b| b.1.cmp(a.1));
-        hash_vec.reverse();
+            let (file, reader) = match self.next_files.pop_front() {
+                Some(next) => next,
+                None => return Ok(None),
+            };
+            self.reader = reader;
+            self.file = file.into();
+            self.pending.clear();
+            self.eof = false;
+            self.record = 0;
+        }
+    }
 
-        let mut assigned_reads: usize = 0;
-        let mut unassigned_reads: usize = 0;
-        let mut ambiguous_reads: usize = 0;
+    /// An empty batch following the last record read, marking the end of the input
+    fn end(&self) -> RecordBatch {
+        RecordBatch {
+            data: Arc::new(Vec::new()),
+            lines: Vec::new(),
+            first: self.record + 1,
+            file: Arc::clone(&self.file),
+        }
+    }
 
-        let mut id_counts = HashMap::new();
+    /// Up to `batch_size` records, or None at the end of the current file
+    fn file_batch(&mut self) -> Result<Option<RecordBatch>, DeezmuxError> {
+        let mut data = std::mem::take(&mut self.pending);
+        let mut lines = Vec::with_capacity(self.batch_size);
+        let mut parsed = 0;
+
+        while lines.len() < self.batch_size {
+            match find_record(&data, parsed, self.eof) {
+                Some((record, end)) => {
+                    let number = self.record + lines.len() + 1;
+                    self.check(&FastqRecord::new(&data, &record), number)?;
+                    lines.push(record);
+                    parsed = end;
+                }
+                None if self.eof => break,
+                None => self.fill(&mut data)?,
+            }
+        }
 
-        for (barcode, count) in hash_vec {
-            let read_barcodes: Vec<&str> = barcode.split("+").collect();
+        let rest = data.split_off(parsed);
+        if lines.len() < self.batch_size && rest.iter().any(|x| !x.is_ascii_whitespace()) {
+            return Err(self.error(self.record + lines.len() + 1, "Truncated record"));
+        }
+        self.pending = rest;
 
-            let mut scores: HashMap<String, u32, RandomHashBuilder64> = Default::default();
+        if lines.is_empty() {
+            return Ok(None);
+        }
 
-            for (bc0, bc1, id, _r1_file, _r2_file) in self.barcodes.iter() {
-                let dist1 = levenshtein(read_barcodes[0].as_bytes(), bc0.as_bytes());
-                let dist2 = levenshtein(read_barcodes[1].as_bytes(), bc1.as_bytes());
+        let batch = RecordBatch {
+            data: Arc::new(data),
+            lines,
+            first: self.record + 1,
+            file: Arc::clone(&self.file),
+        };
+        self.record += batch.len();
 
-                scores.insert(id.clone(), dist1 + dist2);
-            }
+        Ok(Some(batch))
+    }
+}
+
+/// Batches of records, the last one empty to mark the end of the input
+type Records = Receiver<Result<RecordBatch, DeezmuxError>>;
+
+/// Spawns a thread that parses FASTQ records from `files` and sends them down the returned
+/// channel in batches. Stops after the end of the last file or the first error.
+fn spawn_record_reader(s: &thread::Scope<'_>, files: InputFiles) -> Records {
+    let (sender, receiver) = bounded(16);
+    let mut records = RecordReader::new(files);
+
+    s.spawn(move |_| loop {
+        let batch = records
+            .next_batch()
+            .map(|x| x.unwrap_or_else(|| records.end()));
+        let done = !matches!(&batch, Ok(x) if !x.is_empty());
+
+        // The receiver is only dropped when demultiplexing stopped early
+        if sender.send(batch).is_err() || done {
+            break;
+        }
+    });
 
-            let mut scores_vec: Vec<(&String, &u32)> = scores.iter().collect();
-            scores_vec.sort_by(|a, b| b.1.cmp(a.1));
-            scores_vec.reverse();
-
-            let min = scores_vec[0].1;
-            let min_id = scores_vec[0].0;
-
-            // for i in scores_vec[1..].iter() {
-            if min == scores_vec[1].1 && *min <= 4 {
-                // println!("Current Min: {} {} {}", min, min_id, barcode);
-                // println!("Ambiguous found: {} Affecting: {}", scores_vec[1].0, count);
-                ambiguous_reads += count;
-                assigned_barcodes.insert(barcode.clone(), "AMBIGUOUS".to_string());
-            } else if *min <= 4 {
-                assigned_reads += count;
-
-                let e = id_counts.entry(min_id.clone()).or_insert(0);
-                *e += count;
-
-                assigned_barcodes.insert(barcode.clone(), min_id.clone());
-            } else {
-                unassigned_reads += count;
-                assigned_barcodes.insert(barcode.clone(), "UNASSIGNED".to_string());
+    receiver
+}
+
+/// Like `spawn_record_reader`, for files whose records alternate between R1 and R2. Each
+/// batch is split in two, and the halves are sent down the R1 and R2 channels. Batches are
+/// twice the size, so each half lines up with the batches of index files.
+fn spawn_interleaved_reader(s: &thread::Scope<'_>, files: InputFiles) -> (Records, Records) {
+    let (sender_r1, receiver_r1) = bounded(16);
+    let (sender_r2, receiver_r2) = bounded(16);
+    let mut records = RecordReader::new(files);
+    records.batch_size = 2 * BATCH_SIZE;
+
+    s.spawn(move |_| loop {
+        let batch = match records.next_batch() {
+            Ok(batch) => batch.unwrap_or_else(|| records.end()),
+            // R1 is received first, so that is where an error has to go
+            Err(e) => {
+                let _ = sender_r1.send(Err(e));
+                break;
             }
-            // }
+        };
+        let done = batch.is_empty();
+        let (r1, r2) = batch.deinterleave();
+
+        if sender_r1.send(Ok(r1)).is_err() || sender_r2.send(Ok(r2)).is_err() || done {
+            break;
         }
+    });
 
-        /*
+    (receiver_r1, receiver_r2)
+}
 
-        println!("----Finished");
-        println!();
-        println!(
-            "Assigned: {} Unassigned: {} Ambiguous Reads: {}",
-            assigned_reads, unassigned_reads, ambiguous_reads
-        );
+/// Writes the barcode of record `i` into `barcode`, reusing its allocation. With
+/// `from_index` the batches are I1 (and I2) and the barcode is their sequence, otherwise it
+/// is taken from the header of the first batch.
+fn record_barcode(
+    batches: &[RecordBatch],
+    from_index: bool,
+    i: usize,
+    barcode: &mut String,
+) -> Result<(), DeezmuxError> {
+    barcode.clear();
+
+    if !from_index {
+        let batch = &batches[0];
+        let error = |message: String| DeezmuxError::fastq(&batch.file, batch.first + i, message);
+        let header = from_utf8(batch.get(i).header)
+            .map_err(|_| error("Header is not valid UTF-8".to_string()))?;
+        match header_barcode(header) {
+            Some(x) => barcode.push_str(x),
+            None => return Err(error(format!("Header has no barcode: {}", header))),
+        }
+        return Ok(());
+    }
+
+    for batch in batches.iter() {
+        let seq = from_utf8(batch.get(i).seq).map_err(|_| {
+            DeezmuxError::fastq(&batch.file, batch.first + i, "Sequence is not valid UTF-8")
+        })?;
+        if !barcode.is_empty() {
+            barcode.push('+');
+        }
+        barcode.push_str(seq);
+    }
 
-        println!("{:#?}", id_counts);
+    Ok(())
+}
 
-        println!("----And done....");
-        println!();
+/// Header of the first record, None for empty files
+pub fn first_header(files: InputFiles) -> Result<Option<String>, DeezmuxError> {
+    let mut records = RecordReader::new(files);
 
-        println!("{:#?}", assigned_barcodes); */
+    Ok(records
+        .next_batch()?
+        .map(|batch| String::from_utf8_lossy(batch.get(0).header).into_owned()))
+}
 
-        //println!("{:#?}", hash_vec);
+/// Barcodes from the headers of the first `n` records, skipping headers without one
+pub fn first_header_barcodes(files: InputFiles, n: usize) -> Result<Vec<String>, DeezmuxError> {
+    let mut records = RecordReader::new(files);
+    let mut barcodes = Vec::with_capacity(n);
 
-        //println!("{:#?}", counts.get("AAGCACTG+CGATGTTC"));
-        //println!("{:#?}", counts.get("AACTGAGC+TCTTACGG"));
+    while let Some(batch) = records.next_batch()? {
+        for i in 0..batch.len() {
+            if let Some(barcode) = from_utf8(batch.get(i).header).ok().and_then(header_barcode) {
+                barcodes.push(barcode.to_string());
+            }
+            if barcodes.len() == n {
+                return Ok(barcodes);
+            }
+        }
+    }
 
-        /* Split files */
+    Ok(barcodes)
+}
 
-        assigned_barcodes
+/// Barcodes of the first `n` records of the I1 (and I2) files
+pub fn first_index_barcodes(
+    i1: InputFiles,
+    i2: Option<InputFiles>,
+    n: usize,
+) -> Result<Vec<String>, DeezmuxError> {
+    let mut records1 = RecordReader::new(i1);
+    let mut records2 = i2.map(RecordReader::new);
+    let mut barcodes = Vec::with_capacity(n);
+    let mut barcode = String::new();
+
+    while let Some(batch) = records1.next_batch()? {
+        let mut batches = vec![batch];
+        if let Some(records2) = records2.as_mut() {
+            match records2.next_batch()? {
+                Some(batch) => batches.push(batch),
+                None => break,
+            }
+        }
+
+        for i in 0..batches.iter().map(|x| x.len()).min().unwrap_or(0) {
+            record_barcode(&batches, true, i, &mut barcode)?;
+            barcodes.push(barcode.clone());
+
+            if barcodes.len() == n {
+                return Ok(barcodes);
+            }
+        }
     }
+
+    Ok(barcodes)
 }
 
-pub fn split_by_barcodes<R: Read + Send + Sync>(
-    reader: R,
-    suffix: String,
-    output_directory: String,
-    barcodes: Arc<Vec<(String, String, String, String, String)>>,
-    index_files: Option<(&PathBuf, &PathBuf)>,
-) {
-    let (sender, receiver) = bounded(8192);
+/// The read name from a FASTQ header, without the comment field or a trailing /1 or /2
+pub fn read_name(header: &[u8]) -> &[u8] {
+    let name = header
+        .split(|x| x.is_ascii_whitespace())
+        .next()
+        .unwrap_or(&[]);
+    name.strip_suffix(b"/1")
+        .or_else(|| name.strip_suffix(b"/2"))
+        .unwrap_or(name)
+}
 
-    fs::create_dir_all(&output_directory).expect("Unable to create directory");
+/// The barcode from the Casava 1.8 comment of a FASTQ header
+/// (`@<name> <read>:<filtered>:<control>:<index>`), `i7+i5` or just `i7` for single-index
+/// runs. Anything after the comment is ignored. None if the header is not in that format.
+pub fn header_barcode(header: &str) -> Option<&str> {
+    let comment = header.split_whitespace().nth(1)?;
+    let fields: Vec<&str> = comment.split(':').collect();
+
+    let is_number = |x: &str| !x.is_empty() && x.bytes().all(|b| b.is_ascii_digit());
+    let is_index = |x: &str| !x.is_empty() && x.bytes().all(|b| b"ACGTN".contains(&b));
+
+    match fields.as_slice() {
+        [read, filtered, control, index]
+            if is_number(read)
+                && (*filtered == "Y" || *filtered == "N")
+                && is_number(control)
+                && index.split('+').count() <= 2
+                && index.split('+').all(is_index) =>
+        {
+            Some(index)
+        }
+        _ => None,
+    }
+}
 
-    let mut assigned_barcodes: HashMap<String, String, RandomHashBuilder64> = Default::default();
+/// An output file in any `OutputFormat`. Its batches are compressed by the worker pool,
+/// several at a time, and written back in the order they were sent.
+struct OutputWriter {
+    file: BufWriter<File>,
+    path: String,
+    format: OutputFormat,
+    /// Compressed batches still to be written, oldest first
+    pending: VecDeque<Receiver<Vec<u8>>>,
+    max_pending: usize,
+}
 
-    thread::scope(|s| {
-        let mut index_receiver1 = None;
-        let mut index_receiver2 = None;
+impl OutputWriter {
+    fn create(
+        path: String,
+        format: OutputFormat,
+        append: bool,
+        max_pending: usize,
+    ) -> Result<OutputWriter, DeezmuxError> {
+        let mut fh = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .append(append)
+            .truncate(!append)
+            .open(&path)
+            .map_err(DeezmuxError::io(format!("Unable to create {}", path)))?;
+
+        // A merged lane carries on from the last, readers stop at its end of file marker
+        if append {
+            remove_trailer(&mut fh, format.trailer())
+                .map_err(DeezmuxError::io(format!("Unable to append to {}", path)))?;
+        }
 
-        if let Some((idx1, idx2)) = index_files {
-            let (s1, ir1) = bounded(1);
-            let (s2, ir2) = bounded(1);
+        Ok(OutputWriter {
+            file: BufWriter::new(fh),
+            path,
+            format,
+            pending: VecDeque::new(),
+            max_pending,
+        })
+    }
 
-            index_receiver1 = Some(ir1);
-            index_receiver2 = Some(ir2);
+    fn write(&mut self, data: Vec<u8>, pool: &Sender<CompressJob>) -> Result<(), DeezmuxError> {
+        // Nothing to wait for
+        if self.format == OutputFormat::None {
+            return self
+                .file
+                .write_all(&data)
+                .map_err(DeezmuxError::io(format!("Unable to write {}", self.path)));
+        }
 
-            // Read the barcodes...
-            s.spawn(move |_| {
-                let fh1 = File::open(idx1).expect("Unable to open file");
-                let fh1 = MultiGzDecoder::new(BufReader::new(fh1));
-                let fh2 = File::open(idx2).expect("Unable to open file");
-                let fh2 = MultiGzDecoder::new(BufReader::new(fh2));
-
-                let mut lines1 = BufReader::new(fh1).byte_lines();
-                let mut lines2 = BufReader::new(fh2).byte_lines();
-
-                loop {
-                    let header;
-
-                    match lines1.next() {
-                        Some(Ok(line)) => {
-                            header = from_utf8(line)
-                                .expect("FASTQ Header line is not valid UTF-8")
-                                .clone();
-
-                            let entry = (
-                                header.to_string().clone(),
-                                from_utf8(lines1.next().unwrap().unwrap())
-                                    .unwrap()
-                                    .to_string()
-                                    .clone(),
-                            );
-
-                            s1.send(Some(entry)).expect("Error sending Fastq Barcode");
-                            lines1.next();
-                            lines1.next();
-                        }
-                        _ => {
-                            s1.send(None).expect("Unable to send Done command");
-                            break;
-                        }
-                    };
+        let (send, receive) = bounded(1);
+        pool.send((data, send))
+            .expect("Compression workers stopped");
+        self.pending.push_back(receive);
 
-                    let header;
-
-                    match lines2.next() {
-                        Some(Ok(line)) => {
-                            header = from_utf8(line)
-                                .expect("FASTQ Header line is not valid UTF-8")
-                                .clone();
-
-                            let entry = (
-                                header.to_string().clone(),
-                                from_utf8(lines2.next().unwrap().unwrap())
-                                    .unwrap()
-                                    .to_string()
-                                    .clone(),
-                            );
-
-                            s2.send(Some(entry)).expect("Error sending Fastq Barcode");
-                            lines2.next();
-                            lines2.next();
-                        }
-                        _ => {
-                            s2.send(None).expect("Unable to send Done command");
-                            break;
-                        }
-                    };
-                }
-            });
+        while self.pending.len() > self.max_pending {
+            self.write_next()?;
         }
+        Ok(())
+    }
 
-        s.spawn(move |_| {
-            let mut lines = BufReader::new(reader).byte_lines();
+    fn write_next(&mut self) -> Result<(), DeezmuxError> {
+        if let Some(receive) = self.pending.pop_front() {
+            let blocks = receive.recv().expect("Compression worker stopped");
+            self.file
+                .write_all(&blocks)
+                .map_err(DeezmuxError::io(format!("Unable to write {}", self.path)))?;
+        }
+        Ok(())
+    }
 
-            loop {
-                let header;
-                let id;
+    fn finish(mut self) -> Result<(), DeezmuxError> {
+        while !self.pending.is_empty() {
+            self.write_next()?;
+        }
+        self.file
+            .write_all(self.format.trailer())
+            .and_then(|_| self.file.flush())
+            .map_err(DeezmuxError::io(format!("Unable to write {}", self.path)))
+    }
+}
 
-                match lines.next() {
-                    Some(Ok(line)) => {
-                        header = from_utf8(line)
-                            .expect("FASTQ Header line is not valid UTF-8")
-                            .clone();
+/// Truncates `trailer` from the end of the file, if it is there
+fn remove_trailer(file: &mut File, trailer: &[u8]) -> io::Result<()> {
+    let len = file.metadata()?.len();
+    let trailer_len = trailer.len() as u64;
+    if trailer.is_empty() || len < trailer_len {
+        return Ok(());
+    }
 
-                        if index_receiver1.is_some() {
-                            let i1 = index_receiver1.as_ref().unwrap().recv().expect("Error with barcode 1").expect("Error with barcode 1");
-                            let i2 = index_receiver2.as_ref().unwrap().recv().expect("Error with barcode 2").expect("Error with barcode 2");
+    let mut end = vec![0; trailer.len()];
+    file.seek(SeekFrom::Start(len - trailer_len))?;
+    file.read_exact(&mut end)?;
+    if end == trailer {
+        file.set_len(len - trailer_len)?;
+    }
+    Ok(())
+}
 
-                            assert!(header.split_whitespace().next().unwrap() == i1.0.split_whitespace().next().unwrap());
-                            assert!(header.split_whitespace().next().unwrap() == i2.0.split_whitespace().next().unwrap());
+/// Receives the next batch from every input, checking they all hold the same reads. None
+/// once they have all ended.
+fn next_batches(receivers: &[Records]) -> Result<Option<Vec<RecordBatch>>, DeezmuxError> {
+    let mut batches = Vec::with_capacity(receivers.len());
 
-                            id = format!("{}+{}", i1.1, i2.1);
+    for receiver in receivers.iter() {
+        batches.push(receiver.recv().expect("Reader thread stopped")?);
+    }
 
-                        } else {
-                            let n = header.len();
-                            id = header[n - 17..n].to_string().clone();
-                        }
+    // Batches are all the same size until a file ends
+    let shortest = batches.iter().min_by_key(|x| x.len()).unwrap();
+    let longest = batches.iter().max_by_key(|x| x.len()).unwrap();
+    if shortest.len() != longest.len() {
+        return Err(DeezmuxError::fastq(
+            &shortest.file,
+            shortest.first + shortest.len(),
+            format!("File ended before {}", longest.file),
+        ));
+    }
+    if longest.is_empty() {
+        return Ok(None);
+    }
 
-                        let entry = (
-                            id,
-                            header.to_string().clone(),
-                            from_utf8(lines.next().unwrap().unwrap())
-                                .unwrap()
-                                .to_string()
-                                .clone(),
-                            from_utf8(lines.next().unwrap().unwrap())
-                                .unwrap()
-                                .to_string()
-                                .clone(),
-                            from_utf8(lines.next().unwrap().unwrap())
-                                .unwrap()
-                                .to_string()
-                                .clone(),
-                        );
-
-                        sender.send(Some(entry)).expect("Error sending Fastq entry");
-                    }
-                    _ => {
-                        sender.send(None).expect("Unable to send Done command");
-                        break;
-                    }
-                };
+    // Every file has to describe the same reads
+    for batch in batches.iter().skip(1) {
+        for i in 0..batch.len() {
+            let expected = batches[0].get(i).header;
+            let header = batch.get(i).header;
+            if read_name(header) != read_name(expected) {
+                return Err(DeezmuxError::fastq(
+                    &batch.file,
+                    batch.first + i,
+                    format!(
+                        "Read name does not match {}: {} {}",
+                        batches[0].file,
+                        String::from_utf8_lossy(header),
+                        String::from_utf8_lossy(expected)
+                    ),
+                ));
             }
-        });
+        }
+    }
 
-        // END OF THREAD
+    Ok(Some(batches))
+}
 
-        let mut files: HashMap<String, Sender<Option<[String; 4]>>> = HashMap::new();
+/// Where and how `split_by_barcodes` writes its files
+pub struct SplitOutput {
+    pub directory: String,
+    /// Added to every file name, ie: _L001 for per-lane outputs
+    pub suffix: String,
+    /// Outputs already written by an earlier lane of the same run. These are appended to
+    /// instead of replaced, so lanes are merged.
+    pub append_to: Vec<String>,
+    /// Write hopped reads to HOPPED instead of UNASSIGNED
+    pub hopped: bool,
+    /// Write both mates to one file per sample, R1 then R2
+    pub interleaved: bool,
+    /// Worker threads compressing the output
+    pub threads: usize,
+    pub format: OutputFormat,
+    pub level: i32,
+    /// Lanes written to each BAM file, one read group apiece
+    pub read_groups: Vec<Option<PlatformUnit>>,
+    /// The lane being split, the read group of its BAM records
+    pub read_group: Option<PlatformUnit>,
+}
 
-        let mut ids = (*barcodes)
-            .iter()
-            .map(|x| x.2.clone())
-            .collect::<Vec<String>>();
-        ids.push("AMBIGUOUS".to_string());
-        ids.push("UNASSIGNED".to_string());
+/// (sample ID, output name) of every output, a sample listed with different barcodes
+/// shares one output
+pub fn output_names(matcher: &BarcodeMatcher, hopped: bool) -> Vec<(String, String)> {
+    let mut outputs = matcher
+        .samples()
+        .iter()
+        .map(|x| (x.id.clone(), x.output_name.clone()))
+        .collect::<Vec<(String, String)>>();
+    outputs.push((AMBIGUOUS.to_string(), AMBIGUOUS.to_string()));
+    outputs.push((UNASSIGNED.to_string(), UNASSIGNED.to_string()));
+    if hopped {
+        outputs.push((HOPPED.to_string(), HOPPED.to_string()));
+    }
+    outputs
+}
 
-        for id in ids.into_iter() {
-            let (send, r) = bounded(2048);
-            files.insert(id.clone(), send);
+/// Demultiplexes R1 and R2 together. Records are pulled from every file in lockstep,
+/// the barcode is assigned once per pair, and both mates go to the same sample.
+/// Single-end reads (no R2) get one file per sample, as do pairs with interleaved or BAM
+/// output.
+/// `interleaved` reads have both mates in `r1`. Returns the per-sample read counts.
+pub fn split_by_barcodes(
+    r1: InputFiles,
+    r2: Option<InputFiles>,
+    interleaved: bool,
+    matcher: &BarcodeMatcher,
+    index_files: Option<(InputFiles, Option<InputFiles>)>,
+    output: &SplitOutput,
+) -> Result<DemuxStats, DeezmuxError> {
+    fs::create_dir_all(&output.directory).map_err(DeezmuxError::io(format!(
+        "Unable to create directory {}",
+        output.directory
+    )))?;
+
+    // Observed barcode to (assigned sample, output, read count)
+    let mut assigned_barcodes: HashMap<String, (String, usize, usize), RandomHashBuilder64> =
+        Default::default();
 
-            let output_directory = output_directory.clone();
-            let suffix = suffix.clone();
+    thread::scope(|s| {
+        let mut receivers = match interleaved {
+            true => {
+                let (r1, r2) = spawn_interleaved_reader(s, r1);
+                vec![r1, r2]
+            }
+            false => vec![spawn_record_reader(s, r1)],
+        };
+        if let Some(r2) = r2 {
+            receivers.push(spawn_record_reader(s, r2));
+        }
+        let reads = receivers.len();
+        let paired = reads == 2;
+        let separate_r2 = paired && !output.interleaved && output.format != OutputFormat::Bam;
 
-            s.spawn(move |_| {
-                let mut file = BufWriter::new(GzEncoder::new(
-                    File::create(format!("{}/{}_{}.fq.gz", output_directory, id, suffix)).unwrap(),
-                    Compression::fast(),
-                ));
+        if let Some((idx1, idx2)) = index_files {
+            receivers.push(spawn_record_reader(s, idx1));
+            if let Some(idx2) = idx2 {
+                receivers.push(spawn_record_reader(s, idx2));
+            }
+        }
 
-                while let Ok(Some(e1)) = r.recv() {
-                    for e in e1.iter() {
-                        file.write_all(e.as_bytes()).unwrap();
-                        writeln!(file).unwrap();
-                    }
+        // Sample ID to its position in `senders`, `buffers` and `read_groups`
+        let mut files: HashMap<String, usize> = HashMap::new();
+        let mut senders: Vec<Sender<Option<OutputBatch>>> = Vec::new();
+        let mut buffers: Vec<OutputBatch> = Vec::new();
+        let mut read_groups: Vec<String> = Vec::new();
+        let mut writers = Vec::new();
+
+        // Every output shares the compression workers, so one large sample can use them all
+        let threads = output.threads.max(1);
+        let (format, level) = (output.format, output.level);
+        let (pool, jobs) = bounded::<CompressJob>(threads * 2);
+        for _ in 0..threads {
+            let jobs = jobs.clone();
+            s.spawn(move |_| {
+                for (data, result) in jobs.iter() {
+                    let _ = result.send(format.compress(&data, level));
                 }
             });
         }
 
-        while let Ok(Some((id, header, e1, e2, e3))) = receiver.recv() {
-            let x = match assigned_barcodes.get(&id) {
-                Some(x) => x,
-                None => {
-                    let read_barcodes: Vec<&str> = id.split("+").collect();
-                    let mut scores: HashMap<String, u32, RandomHashBuilder64> = Default::default();
-                    for (bc0, bc1, id, _r1_file, _r2_file) in barcodes.iter() {
-                        let dist1 = levenshtein(read_barcodes[0].as_bytes(), bc0.as_bytes());
-                        let dist2 = levenshtein(read_barcodes[1].as_bytes(), bc1.as_bytes());
+        for (id, output_name) in output_names(matcher, output.hopped).into_iter() {
+            if files.contains_key(&id) {
+                continue;
+            }
+
+            let append = output.append_to.contains(&output_name);
+
+            // A new BAM file starts with the read groups of every lane it will hold
+            let header = (format == OutputFormat::Bam && !append).then(|| {
+                let library = matcher
+                    .samples()
+                    .iter()
+                    .find(|x| x.id == id)
+                    .map_or(id.as_str(), sample_library);
+                bam_header(&id, library, &output.read_groups)
+            });
+            read_groups.push(read_group_id(&id, &output.read_group));
+
+            let (send, r) = bounded(4);
+            files.insert(id, senders.len());
+            senders.push(send);
+            buffers.push(Default::default());
+
+            let path = |read: &str| {
+                format!(
+                    "{}/{}{}{}{}",
+                    output.directory,
+                    output_name,
+                    output.suffix,
+                    read,
+                    format.extension()
+                )
+            };
+            let path_r1 = path(if separate_r2 { "_r1" } else { "" });
+            let path_r2 = separate_r2.then(|| path("_r2"));
+
+            let pool = pool.clone();
+
+            writers.push(s.spawn(move |_| -> Result<(), DeezmuxError> {
+                let mut out_r1 = OutputWriter::create(path_r1, format, append, threads)?;
+                let mut out_r2 = match path_r2 {
+                    Some(path) => Some(OutputWriter::create(path, format, append, threads)?),
+                    None => None,
+                };
+                if let Some(header) = header {
+                    out_r1.write(header, &pool)?;
+                }
 
-                        scores.insert(id.clone(), dist1 + dist2);
+                while let Ok(Some((r1, r2))) = r.recv() {
+                    out_r1.write(r1, &pool)?;
+                    if let Some(out) = out_r2.as_mut() {
+                        out.write(r2, &pool)?;
                     }
-                    let mut scores_vec: Vec<(&String, &u32)> = scores.iter().collect();
-                    scores_vec.sort_by(|a, b| b.1.cmp(a.1));
-                    scores_vec.reverse();
+                }
+
+                out_r1.finish()?;
+                if let Some(out) = out_r2 {
+                    out.finish()?;
+                }
+
+                Ok(())
+            }));
+        }
 
-                    let min = scores_vec[0].1;
-                    let min_id = scores_vec[0].0;
+        let hopped = output.hopped;
+        let mut demultiplex = || -> Result<(), DeezmuxError> {
+            let mut barcode = String::new();
+            let mut barcode_quality = Vec::new();
 
-                    if min == scores_vec[1].1 && *min <= 4 {
-                        assigned_barcodes.insert(id.clone(), "AMBIGUOUS".to_string());
-                    } else if *min <= 4 {
-                        assigned_barcodes.insert(id.clone(), min_id.clone());
+            while let Some(batches) = next_batches(&receivers)? {
+                let from_index = batches.len() > reads;
+                let barcodes = match from_index {
+                    true => &batches[reads..],
+                    false => &batches[..1],
+                };
+
+                for i in 0..batches[0].len() {
+                    record_barcode(barcodes, from_index, i, &mut barcode)?;
+
+                    let output = match assigned_barcodes.get_mut(barcode.as_str()) {
+                        Some((_, output, count)) => {
+                            *count += 1;
+                            *output
+                        }
+                        None => {
+                            // Hopped reads are still counted as hopped without a HOPPED file
+                            let x = matcher.assign(&barcode);
+                            let output = match x {
+                                HOPPED if !hopped => files[UNASSIGNED],
+                                x => files[x],
+                            };
+                            assigned_barcodes.insert(barcode.clone(), (x.to_string(), output, 1));
+                            output
+                        }
+                    };
+
+                    let buffer = &mut buffers[output];
+                    if format == OutputFormat::Bam {
+                        // Qualities of both index reads, separated like the barcodes are
+                        barcode_quality.clear();
+                        if from_index {
+                            for (j, batch) in barcodes.iter().enumerate() {
+                                if j > 0 {
+                                    barcode_quality.push(b' ');
+                                }
+                                barcode_quality.extend_from_slice(batch.get(i).qual);
+                            }
+                        }
+
+                        let read_group = &read_groups[output];
+                        let flag = if paired { FIRST_OF_PAIR } else { UNPAIRED };
+                        let mut write = |record: &FastqRecord, flag: u16| {
+                            write_bam_record(
+                                &mut buffer.0,
+                                record,
+                                flag,
+                                read_group,
+                                &barcode,
+                                &barcode_quality,
+                            )
+                        };
+                        write(&batches[0].get(i), flag);
+                        if paired {
+                            write(&batches[1].get(i), SECOND_OF_PAIR);
+                        }
                     } else {
-                        assigned_barcodes.insert(id.clone(), "UNASSIGNED".to_string());
+                        batches[0].get(i).write_to(&mut buffer.0);
+                        if separate_r2 {
+                            batches[1].get(i).write_to(&mut buffer.1);
+                        } else if paired {
+                            batches[1].get(i).write_to(&mut buffer.0);
+                        }
+                    }
+
+                    // A writer only hangs up after an error, which is reported when it is joined
+                    if buffer.0.len() >= OUTPUT_BATCH_SIZE
+                        && senders[output].send(Some(std::mem::take(buffer))).is_err()
+                    {
+                        return Ok(());
                     }
+                }
+            }
 
-                    assigned_barcodes.get(&id).unwrap()
+            for (sender, buffer) in senders.iter().zip(buffers.iter_mut()) {
+                if !buffer.0.is_empty() && sender.send(Some(std::mem::take(buffer))).is_err() {
+                    return Ok(());
                 }
-            };
+            }
+
+            Ok(())
+        };
 
-            let send = files.get_mut(x).unwrap();
-            send.send(Some([header, e1, e2, e3]))
-                .expect("Error sending Fastq entry");
+        let result = demultiplex();
+
+        // Always let the writers finish their files, even when stopping on an error
+        for i in senders.iter() {
+            let _ = i.send(None);
         }
 
-        for i in files.values_mut() {
-            i.send(None).expect("Unable to send Finish command");
+        for writer in writers {
+            writer.join().expect("Writer thread panicked")?;
         }
+
+        result
     })
-    .unwrap();
+    .expect("Unable to properly scope")?;
+
+    Ok(DemuxStats::new(
+        matcher,
+        assigned_barcodes
+            .iter()
+            .map(|(barcode, (id, _, count))| (barcode.as_str(), id.as_str(), *count)),
+    ))
 }
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::bgzf::EOF_BLOCK;
 
     #[test]
     fn new_fastq() {
@@ -435,5 +1058,715 @@ mod tests {
             "@MT_E00516:746:HG3WYCCX2:6:1101:2229:1661 1:N:0:NGAGCTAG+NAGCCTGA\nNTG\n+\n#AA\n",
         );
         // split_fastq_by_id(not_really_a_fastq.as_bytes(), "test");
+        let mut records =
+            RecordReader::new(vec![("test".to_string(), not_really_a_fastq.as_bytes())]);
+        let batch = records.next_batch().unwrap().unwrap();
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch.get(0).seq, b"NTG");
+        assert_eq!(batch.get(0).qual, b"#AA");
+        assert!(records.next_batch().unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_fastq() {
+        let truncated = "@a 1:N:0:ACGT\nACGT\n+\nIIII\n@b 1:N:0:ACGT\nACGT\n";
+        let mut records = RecordReader::new(vec![("test".to_string(), truncated.as_bytes())]);
+
+        match records.next_batch() {
+            Err(DeezmuxError::FastqFormat { record, .. }) => assert_eq!(record, 2),
+            _ => panic!("Expected a FASTQ format error"),
+        }
+    }
+
+    #[test]
+    fn malformed_fastq() {
+        let error =
+            |fastq: &str| match RecordReader::new(vec![("test".to_string(), fastq.as_bytes())])
+                .next_batch()
+            {
+                Err(DeezmuxError::FastqFormat {
+                    record, message, ..
+                }) => (record, message),
+                _ => panic!("Expected a FASTQ format error"),
+            };
+
+        assert_eq!(
+            error("@a\nACGT\n+\nIIII\nb\nACGT\n+\nIIII\n"),
+            (2, "Header does not start with @".to_string())
+        );
+        assert_eq!(
+            error("@a\nACGT\n-\nIIII\n"),
+            (1, "Separator line does not start with +".to_string())
+        );
+        assert_eq!(
+            error("@a\nACGT\n+\nIII\n"),
+            (
+                1,
+                "Sequence and quality have different lengths (4 and 3)".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn records_numbered_per_file() {
+        let files = vec![
+            ("a.fq".to_string(), "@a\nACGT\n+\nIIII\n".as_bytes()),
+            (
+                "b.fq".to_string(),
+                "@b\nACGT\n+\nIIII\n@c\nACGT\n-\nIIII\n".as_bytes(),
+            ),
+        ];
+        let mut records = RecordReader::new(files);
+
+        let batch = records.next_batch().unwrap().unwrap();
+        assert_eq!((&*batch.file, batch.first, batch.len()), ("a.fq", 1, 1));
+
+        let error = records.next_batch().err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Invalid FASTQ in b.fq at record 2: Separator line does not start with +"
+        );
+    }
+
+    #[test]
+    fn records_across_reads() {
+        // Each read stops partway through a record, and the last line has no newline
+        let reader = "@a\r\nAC"
+            .as_bytes()
+            .chain("GT\r\n+\r\nIIII\r\n@b\nA\n+\nI".as_bytes());
+        let mut records = RecordReader::new(vec![("test".to_string(), reader)]);
+
+        let batch = records.next_batch().unwrap().unwrap();
+        assert_eq!(batch.len(), 2);
+        assert_eq!(
+            batch.get(0),
+            FastqRecord {
+                header: b"@a",
+                seq: b"ACGT",
+                plus: b"+",
+                qual: b"IIII",
+            }
+        );
+
+        let mut out = Vec::new();
+        batch.get(1).write_to(&mut out);
+        assert_eq!(out, b"@b\nA\n+\nI\n");
+    }
+
+    #[test]
+    fn barcode_from_header() {
+        assert_eq!(
+            header_barcode("@MT_E00516:746:HG3WYCCX2:6:1101:2229:1661 1:N:0:NGAGCTAG+NAGCCTGA"),
+            Some("NGAGCTAG+NAGCCTGA")
+        );
+        assert_eq!(
+            header_barcode("@MT_E00516:746:HG3WYCCX2:6:1101:2229:1661 1:N:0:NGAGCTAGTT"),
+            Some("NGAGCTAGTT")
+        );
+        assert_eq!(
+            header_barcode("@A00123:8:H7:1:1101:1000:1000 2:Y:0:ACGTAC+GTACGTACGTAC extra=1"),
+            Some("ACGTAC+GTACGTACGTAC")
+        );
+        assert_eq!(header_barcode("@read1"), None);
+        assert_eq!(header_barcode("@read1 1:N:0:1"), None);
+        assert_eq!(header_barcode("@read1 1:N:0:ACGT+ACGT+ACGT"), None);
+    }
+
+    #[test]
+    fn read_names_match_across_mates() {
+        assert_eq!(
+            read_name(b"@MT_E00516:746:HG3WYCCX2:6:1101:2229:1661 1:N:0:NGAGCTAG+NAGCCTGA"),
+            read_name(b"@MT_E00516:746:HG3WYCCX2:6:1101:2229:1661 2:N:0:NGAGCTAG+NAGCCTGA")
+        );
+        assert_eq!(read_name(b"@read1/1"), read_name(b"@read1/2"));
+    }
+
+    /// One input file holding `fastq`
+    fn input(name: &str, fastq: &str) -> InputFiles {
+        let reader: InputReader = Box::new(io::Cursor::new(fastq.as_bytes().to_vec()));
+        vec![(name.to_string(), reader)]
+    }
+
+    /// Output to a fresh directory `name` in the temp dir
+    fn test_output(name: &str, format: OutputFormat, interleaved: bool) -> SplitOutput {
+        let directory = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&directory);
+
+        SplitOutput {
+            directory: directory.to_str().unwrap().to_string(),
+            suffix: String::new(),
+            append_to: Vec::new(),
+            hopped: false,
+            interleaved,
+            threads: 2,
+            level: format.default_level(),
+            format,
+            read_groups: Vec::new(),
+            read_group: None,
+        }
+    }
+
+    /// A file written by `split_by_barcodes`, decompressed
+    fn read_output(output: &SplitOutput, name: &str) -> Vec<u8> {
+        let fh = File::open(format!("{}/{}", output.directory, name)).unwrap();
+        let mut out = Vec::new();
+        DecompressReader::new(fh, 1).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    fn dual_index_sheet() -> SampleSheet {
+        vec![
+            Sample::new("S1", "AAAAAAAA", Some("CCCCCCCC")),
+            Sample::new("S2", "GGGGGGGG", Some("TTTTTTTT")),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    #[test]
+    fn count_header_barcodes() {
+        let r1 = "@a 1:N:0:AAAAAAAA+CCCCCCCC\nACGT\n+\nIIII\n\
+                  @b 1:N:0:AAAAAAAT+CCCCCCCC\nACGT\n+\nIIII\n\
+                  @c 1:N:0:GGGGGGGG+TTTTTTTT\nACGT\n+\nIIII\n\
+                  @d 1:N:0:CACACACA+GTGTGTGT\nACGT\n+\nIIII\n";
+
+        let stats = FastqSplitter::new()
+            .with_mm(1, 1)
+            .with_match_mode(MatchMode::Hamming)
+            .with_barcodes(dual_index_sheet())
+            .match_header_barcodes(input("r1.fq", r1))
+            .unwrap();
+
+        assert_eq!(stats.total_reads, 4);
+        assert_eq!(stats.assigned_reads, 3);
+        assert_eq!(stats.unassigned_reads, 1);
+        assert_eq!(stats.samples[0].id, "S1");
+        assert_eq!(stats.samples[0].reads, 2);
+        assert_eq!(stats.samples[0].perfect_index_reads, 1);
+        assert_eq!(stats.samples[0].mismatched_index_reads, 1);
+        assert_eq!(stats.samples[1].reads, 1);
+        assert_eq!(
+            stats.undetermined,
+            vec![("CACACACA+GTGTGTGT".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn count_index_barcodes() {
+        let i1 = "@a 1:N:0:1\nAAAAAAAA\n+\nIIIIIIII\n\
+                  @b 1:N:0:1\nGGGGGGGG\n+\nIIIIIIII\n\
+                  @c 1:N:0:1\nAAAAAAAA\n+\nIIIIIIII\n";
+        let i2 = "@a 1:N:0:1\nCCCCCCCC\n+\nIIIIIIII\n\
+                  @b 1:N:0:1\nTTTTTTTT\n+\nIIIIIIII\n\
+                  @c 1:N:0:1\nTTTTTTTT\n+\nIIIIIIII\n";
+
+        let stats = FastqSplitter::new()
+            .with_mm(1, 1)
+            .with_barcodes(dual_index_sheet())
+            .match_index_barcodes(input("i1.fq", i1), Some(input("i2.fq", i2)))
+            .unwrap();
+
+        assert_eq!(stats.total_reads, 3);
+        assert_eq!(stats.assigned_reads, 2);
+        assert_eq!(stats.samples[0].perfect_index_reads, 1);
+        assert_eq!(stats.samples[1].perfect_index_reads, 1);
+        // i7 of S1 with i5 of S2
+        assert_eq!(stats.hopped_reads, 1);
+        assert_eq!(
+            stats.index_hopping.unwrap().counts,
+            vec![vec![1, 1], vec![0, 1]]
+        );
+    }
+
+    #[test]
+    fn split_pairs_together() {
+        let r1 = "@a 1:N:0:AAAAAAAA+CCCCCCCC\nACGT\n+\nIIII\n\
+                  @b 1:N:0:GGGGGGGG+TTTTTTTT\nTTTT\n+\nIIII\n";
+        let r2 = "@a 2:N:0:AAAAAAAA+CCCCCCCC\nGGGG\n+\nIIII\n\
+                  @b 2:N:0:GGGGGGGG+TTTTTTTT\nCCCC\n+\nIIII\n";
+
+        let barcodes = dual_index_sheet();
+
+        let output = test_output("deezmux_split_pairs_together", OutputFormat::Gz, false);
+        let stats = split_by_barcodes(
+            input("r1.fq", r1),
+            Some(input("r2.fq", r2)),
+            false,
+            &BarcodeMatcher::new(MatchMode::Levenshtein, barcodes, (2, 2)),
+            None,
+            &output,
+        )
+        .unwrap();
+        assert_eq!(stats.assigned_reads, 2);
+
+        assert_eq!(
+            read_output(&output, "S1_r1.fq.gz"),
+            b"@a 1:N:0:AAAAAAAA+CCCCCCCC\nACGT\n+\nIIII\n"
+        );
+        assert_eq!(
+            read_output(&output, "S1_r2.fq.gz"),
+            b"@a 2:N:0:AAAAAAAA+CCCCCCCC\nGGGG\n+\nIIII\n"
+        );
+        assert_eq!(
+            read_output(&output, "S2_r2.fq.gz"),
+            b"@b 2:N:0:GGGGGGGG+TTTTTTTT\nCCCC\n+\nIIII\n"
+        );
+        assert_eq!(read_output(&output, "UNASSIGNED_r1.fq.gz"), b"");
+
+        fs::remove_dir_all(&output.directory).unwrap();
+    }
+
+    #[test]
+    fn output_batches_stay_in_order() {
+        // Enough for several OUTPUT_BATCH_SIZE batches in each sample
+        let mut r1 = String::new();
+        let mut r2 = String::new();
+        let mut expected: [[String; 2]; 2] = Default::default();
+        let seq = "ACGT".repeat(40);
+        let qual = "I".repeat(seq.len());
+        let mut i = 0;
+        while expected[1][0].len() < 3 * OUTPUT_BATCH_SIZE {
+            let barcode = ["AAAAAAAA", "GGGGGGGG"][i % 2];
+            let mate =
+                |read: usize| format!("@r{} {}:N:0:{}\n{}\n+\n{}\n", i, read, barcode, seq, qual);
+            r1.push_str(&mate(1));
+            r2.push_str(&mate(2));
+            expected[i % 2][0].push_str(&mate(1));
+            expected[i % 2][1].push_str(&mate(2));
+            i += 1;
+        }
+
+        let barcodes: SampleSheet = vec![
+            Sample::new("S1", "AAAAAAAA", None),
+            Sample::new("S2", "GGGGGGGG", None),
+        ]
+        .into_iter()
+        .collect();
+        let output = test_output("deezmux_output_batches", OutputFormat::Gz, false);
+        let stats = split_by_barcodes(
+            input("r1.fq", &r1),
+            Some(input("r2.fq", &r2)),
+            false,
+            &BarcodeMatcher::new(MatchMode::Hamming, barcodes, (1, 1)),
+            None,
+            &output,
+        )
+        .unwrap();
+        assert_eq!(stats.assigned_reads, i);
+
+        for (sample, [r1, r2]) in ["S1", "S2"].iter().zip(expected.iter()) {
+            let name = |read: &str| format!("{}_{}.fq.gz", sample, read);
+            assert_eq!(read_output(&output, &name("r1")), r1.as_bytes());
+            assert_eq!(read_output(&output, &name("r2")), r2.as_bytes());
+        }
+
+        fs::remove_dir_all(&output.directory).unwrap();
+    }
+
+    #[test]
+    fn merged_lanes_end_once() {
+        let lane = |n: usize| format!("@l{} 1:N:0:AAAAAAAA\nACGT\n+\nIIII\n", n);
+        let barcodes: SampleSheet = vec![Sample::new("S1", "AAAAAAAA", None)]
+            .into_iter()
+            .collect();
+        let matcher = BarcodeMatcher::new(MatchMode::Hamming, barcodes, (1, 1));
+
+        let mut output = test_output("deezmux_merged_lanes_end_once", OutputFormat::Gz, false);
+        for n in 1..=2 {
+            let r1 = lane(n);
+            split_by_barcodes(input("r1.fq", &r1), None, false, &matcher, None, &output).unwrap();
+            output.append_to = output_names(&matcher, false)
+                .into_iter()
+                .map(|x| x.1)
+                .collect();
+        }
+
+        assert_eq!(
+            read_output(&output, "S1.fq.gz"),
+            (lane(1) + &lane(2)).as_bytes()
+        );
+
+        // Only the last lane is followed by the end of file marker
+        for name in ["S1.fq.gz", "UNASSIGNED.fq.gz"] {
+            let data = fs::read(format!("{}/{}", output.directory, name)).unwrap();
+            let markers = data.windows(EOF_BLOCK.len()).filter(|x| *x == EOF_BLOCK);
+            assert_eq!(markers.count(), 1, "{}", name);
+            assert!(data.ends_with(&EOF_BLOCK));
+        }
+
+        fs::remove_dir_all(&output.directory).unwrap();
+    }
+
+    #[test]
+    fn merged_lanes_bam() {
+        let read = |lane: u32, mate: u32, seq: &str| {
+            format!(
+                "@A:1:FC:{}:1101:1:1 {}:N:0:AAAAAAAA\n{}\n+\n{}\n",
+                lane,
+                mate,
+                seq,
+                "I".repeat(seq.len())
+            )
+        };
+        let barcodes: SampleSheet = vec![Sample::new("S1", "AAAAAAAA", None)]
+            .into_iter()
+            .collect();
+        let matcher = BarcodeMatcher::new(MatchMode::Hamming, barcodes, (1, 1));
+
+        let units: Vec<Option<PlatformUnit>> = (1..=2)
+            .map(|lane| PlatformUnit::from_header(&format!("@A:1:FC:{}:1101:1:1", lane)))
+            .collect();
+        let mut output = test_output("deezmux_merged_lanes_bam", OutputFormat::Bam, false);
+        output.read_groups = units.clone();
+        for (lane, unit) in (1..=2).zip(units) {
+            output.read_group = unit;
+            let (r1, r2) = (read(lane, 1, "ACG"), read(lane, 2, "TTTT"));
+            split_by_barcodes(
+                input("r1.fq", &r1),
+                Some(input("r2.fq", &r2)),
+                false,
+                &matcher,
+                None,
+                &output,
+            )
+            .unwrap();
+            output.append_to = vec!["S1".to_string()];
+        }
+
+        let data = read_output(&output, "S1.bam");
+        let int = |at: usize| i32::from_le_bytes(data[at..at + 4].try_into().unwrap()) as usize;
+        assert_eq!(&data[..4], b"BAM\x01");
+        let text = String::from_utf8(data[8..8 + int(4)].to_vec()).unwrap();
+        assert!(text.contains("@RG\tID:S1.FC.1\tSM:S1\tLB:S1\tPL:ILLUMINA\tPU:FC.1\n"));
+        assert!(text.contains("@RG\tID:S1.FC.2\tSM:S1\tLB:S1\tPL:ILLUMINA\tPU:FC.2\n"));
+        assert_eq!(int(8 + text.len()), 0);
+
+        // (flag, length, read group) of every record, both lanes after the one header
+        let mut records = Vec::new();
+        let mut at = 12 + text.len();
+        while at < data.len() {
+            let record = &data[at + 4..at + 4 + int(at)];
+            let flag = u16::from_le_bytes([record[14], record[15]]);
+            let tags = String::from_utf8_lossy(record).into_owned();
+            let read_group = tags
+                .split("RGZ")
+                .nth(1)
+                .unwrap()
+                .split('\0')
+                .next()
+                .unwrap();
+            records.push((flag, int(at + 20), read_group.to_string()));
+            at += 4 + int(at);
+        }
+        assert_eq!(at, data.len());
+        assert_eq!(
+            records,
+            vec![
+                (FIRST_OF_PAIR, 3, "S1.FC.1".to_string()),
+                (SECOND_OF_PAIR, 4, "S1.FC.1".to_string()),
+                (FIRST_OF_PAIR, 3, "S1.FC.2".to_string()),
+                (SECOND_OF_PAIR, 4, "S1.FC.2".to_string()),
+            ]
+        );
+
+        // htslib stops reading at the first end of file marker
+        let compressed = fs::read(format!("{}/S1.bam", output.directory)).unwrap();
+        let markers = compressed
+            .windows(EOF_BLOCK.len())
+            .filter(|x| *x == EOF_BLOCK);
+        assert_eq!(markers.count(), 1);
+
+        fs::remove_dir_all(&output.directory).unwrap();
+    }
+
+    #[test]
+    fn hopped_reads_without_hopped_output() {
+        let r1 = "@a 1:N:0:AAAAAAAA+CCCCCCCC\nACGT\n+\nIIII\n\
+                  @b 1:N:0:AAAAAAAA+TTTTTTTT\nTTTT\n+\nIIII\n\
+                  @c 1:N:0:ACACACAC+ACACACAC\nGGGG\n+\nIIII\n";
+
+        let barcodes = dual_index_sheet();
+        let matcher = BarcodeMatcher::new(MatchMode::Hamming, barcodes, (1, 1));
+
+        let output = test_output("deezmux_hopped_reads", OutputFormat::None, false);
+        let stats =
+            split_by_barcodes(input("r1.fq", r1), None, false, &matcher, None, &output).unwrap();
+
+        // The hopped read is written to UNASSIGNED, but counted as hopped
+        assert_eq!(
+            read_output(&output, "UNASSIGNED.fq"),
+            b"@b 1:N:0:AAAAAAAA+TTTTTTTT\nTTTT\n+\nIIII\n\
+              @c 1:N:0:ACACACAC+ACACACAC\nGGGG\n+\nIIII\n"
+        );
+        assert_eq!((stats.unassigned_reads, stats.hopped_reads), (1, 1));
+        assert_eq!(stats.hopping_rate, 0.5);
+        assert_eq!(
+            stats.undetermined,
+            vec![("ACACACAC+ACACACAC".to_string(), 1)]
+        );
+
+        let mut output = output;
+        output.hopped = true;
+        let stats =
+            split_by_barcodes(input("r1.fq", r1), None, false, &matcher, None, &output).unwrap();
+        assert_eq!((stats.unassigned_reads, stats.hopped_reads), (1, 1));
+        assert_eq!(
+            read_output(&output, "HOPPED.fq"),
+            b"@b 1:N:0:AAAAAAAA+TTTTTTTT\nTTTT\n+\nIIII\n"
+        );
+
+        fs::remove_dir_all(&output.directory).unwrap();
+    }
+
+    #[test]
+    fn split_malformed_header() {
+        let r1 = "@a 1:N:0:AAAAAAAA\nACGT\n+\nIIII\n\
+                  @b 1:N:0:1\nTTTT\n+\nIIII\n";
+        let barcodes: SampleSheet = vec![Sample::new("S1", "AAAAAAAA", None)]
+            .into_iter()
+            .collect();
+        let matcher = BarcodeMatcher::new(MatchMode::Hamming, barcodes, (1, 1));
+
+        let output = test_output("deezmux_split_malformed_header", OutputFormat::None, false);
+        let error = split_by_barcodes(input("lane1.fq", r1), None, false, &matcher, None, &output)
+            .unwrap_err();
+        assert!(matches!(
+            &error,
+            DeezmuxError::FastqFormat { file, record: 2, .. } if file == "lane1.fq"
+        ));
+        assert_eq!(
+            error.to_string(),
+            "Invalid FASTQ in lane1.fq at record 2: Header has no barcode: @b 1:N:0:1"
+        );
+
+        fs::remove_dir_all(&output.directory).unwrap();
+    }
+
+    #[test]
+    fn split_single_end() {
+        let r1 = "@a 1:N:0:AAAAAAAA\nACGT\n+\nIIII\n\
+                  @b 1:N:0:GGGGGGGG\nTTTT\n+\nIIII\n";
+
+        let barcodes: SampleSheet = vec![Sample::new("S1", "AAAAAAAA", None)]
+            .into_iter()
+            .collect();
+
+        let output = test_output("deezmux_split_single_end", OutputFormat::Gz, false);
+        let stats = split_by_barcodes(
+            input("r1.fq", r1),
+            None,
+            false,
+            &BarcodeMatcher::new(MatchMode::Hamming, barcodes, (1, 1)),
+            None,
+            &output,
+        )
+        .unwrap();
+        assert_eq!(stats.assigned_reads, 1);
+        assert_eq!(stats.unassigned_reads, 1);
+
+        assert_eq!(
+            read_output(&output, "S1.fq.gz"),
+            b"@a 1:N:0:AAAAAAAA\nACGT\n+\nIIII\n"
+        );
+        assert_eq!(
+            read_output(&output, "UNASSIGNED.fq.gz"),
+            b"@b 1:N:0:GGGGGGGG\nTTTT\n+\nIIII\n"
+        );
+        assert!(!Path::new(&format!("{}/S1_r2.fq.gz", output.directory)).exists());
+
+        fs::remove_dir_all(&output.directory).unwrap();
+    }
+
+    #[test]
+    fn split_interleaved() {
+        let interleaved = "@a/1\nACGT\n+\nIIII\n@a/2\nGGGG\n+\nIIII\n\
+                           @b/1\nTTTT\n+\nIIII\n@b/2\nCCCC\n+\nIIII\n";
+        let i1 = "@a\nAAAAAAAA\n+\nIIIIIIII\n@b\nGGGGGGGG\n+\nIIIIIIII\n";
+
+        let barcodes: SampleSheet = vec![Sample::new("S1", "AAAAAAAA", None)]
+            .into_iter()
+            .collect();
+
+        let output = test_output("deezmux_split_interleaved", OutputFormat::None, false);
+        let index = input("i1.fq", i1);
+        let stats = split_by_barcodes(
+            input("interleaved.fq", interleaved),
+            None,
+            true,
+            &BarcodeMatcher::new(MatchMode::Hamming, barcodes, (1, 1)),
+            Some((index, None)),
+            &output,
+        )
+        .unwrap();
+        assert_eq!(stats.assigned_reads, 1);
+        assert_eq!(stats.unassigned_reads, 1);
+
+        assert_eq!(read_output(&output, "S1_r1.fq"), b"@a/1\nACGT\n+\nIIII\n");
+        assert_eq!(read_output(&output, "S1_r2.fq"), b"@a/2\nGGGG\n+\nIIII\n");
+        assert_eq!(
+            read_output(&output, "UNASSIGNED_r2.fq"),
+            b"@b/2\nCCCC\n+\nIIII\n"
+        );
+
+        fs::remove_dir_all(&output.directory).unwrap();
+
+        // An odd number of records leaves R1 without its mate
+        let odd = &interleaved[..interleaved.len() - "@b/2\nCCCC\n+\nIIII\n".len()];
+        let index = input("i1.fq", i1);
+        let result = split_by_barcodes(
+            input("interleaved.fq", odd),
+            None,
+            true,
+            &BarcodeMatcher::new(MatchMode::Hamming, SampleSheet::new(), (1, 1)),
+            Some((index, None)),
+            &output,
+        );
+        match result {
+            Err(DeezmuxError::FastqFormat { file, record, .. }) => {
+                assert_eq!((file.as_str(), record), ("interleaved.fq", 2))
+            }
+            _ => panic!("Expected a FASTQ format error"),
+        }
+
+        fs::remove_dir_all(&output.directory).unwrap();
+    }
+
+    #[test]
+    fn interleaved_with_index_batches() {
+        let pairs = BATCH_SIZE + 1000;
+        let mut interleaved = String::new();
+        let mut i1 = String::new();
+        for i in 0..pairs {
+            interleaved.push_str(&format!(
+                "@p{0}/1\nACGT\n+\nIIII\n@p{0}/2\nTTTT\n+\nIIII\n",
+                i
+            ));
+            i1.push_str(&format!("@p{}\nAAAAAAAA\n+\nIIIIIIII\n", i));
+        }
+
+        // Each mate gets a full batch, as long as those of the index file
+        thread::scope(|s| {
+            let (r1, r2) = spawn_interleaved_reader(s, input("interleaved.fq", &interleaved));
+            let index = spawn_record_reader(s, input("i1.fq", &i1));
+            let batches = next_batches(&[r1, r2, index]).unwrap().unwrap();
+            assert!(batches.iter().all(|x| x.len() == BATCH_SIZE));
+        })
+        .unwrap();
+
+        let barcodes: SampleSheet = vec![Sample::new("S1", "AAAAAAAA", None)]
+            .into_iter()
+            .collect();
+        let output = test_output(
+            "deezmux_interleaved_index_batches",
+            OutputFormat::None,
+            false,
+        );
+        let stats = split_by_barcodes(
+            input("interleaved.fq", &interleaved),
+            None,
+            true,
+            &BarcodeMatcher::new(MatchMode::Hamming, barcodes, (1, 1)),
+            Some((input("i1.fq", &i1), None)),
+            &output,
+        )
+        .unwrap();
+        assert_eq!(stats.assigned_reads, pairs);
+
+        fs::remove_dir_all(&output.directory).unwrap();
+    }
+
+    #[test]
+    fn interleaved_index_output_modes() {
+        // Several batches of pairs, alternating between two samples
+        let pairs = 2 * BATCH_SIZE + 100;
+        let mut interleaved = String::new();
+        let mut i1 = String::new();
+        // R1, R2 and both interleaved, of each sample
+        let mut expected: [[String; 3]; 2] = Default::default();
+        for i in 0..pairs {
+            let r1 = format!("@p{}/1\nACGT\n+\nIIII\n", i);
+            let r2 = format!("@p{}/2\nTTTT\n+\nIIII\n", i);
+            interleaved.push_str(&r1);
+            interleaved.push_str(&r2);
+            i1.push_str(&format!(
+                "@p{}\n{}\n+\nIIIIIIII\n",
+                i,
+                ["AAAAAAAA", "GGGGGGGG"][i % 2]
+            ));
+            expected[i % 2][0].push_str(&r1);
+            expected[i % 2][1].push_str(&r2);
+            expected[i % 2][2].push_str(&(r1 + &r2));
+        }
+
+        let barcodes: SampleSheet = vec![
+            Sample::new("S1", "AAAAAAAA", None),
+            Sample::new("S2", "GGGGGGGG", None),
+        ]
+        .into_iter()
+        .collect();
+        let matcher = BarcodeMatcher::new(MatchMode::Hamming, barcodes, (1, 1));
+
+        for interleaved_output in [false, true] {
+            let output = test_output(
+                "deezmux_interleaved_index_output_modes",
+                OutputFormat::None,
+                interleaved_output,
+            );
+            let stats = split_by_barcodes(
+                input("interleaved.fq", &interleaved),
+                None,
+                true,
+                &matcher,
+                Some((input("i1.fq", &i1), None)),
+                &output,
+            )
+            .unwrap();
+            assert_eq!(stats.assigned_reads, pairs);
+
+            for (sample, [r1, r2, both]) in ["S1", "S2"].iter().zip(expected.iter()) {
+                match interleaved_output {
+                    false => {
+                        let name = |read: &str| format!("{}_{}.fq", sample, read);
+                        assert_eq!(read_output(&output, &name("r1")), r1.as_bytes());
+                        assert_eq!(read_output(&output, &name("r2")), r2.as_bytes());
+                    }
+                    true => {
+                        let name = format!("{}.fq", sample);
+                        assert_eq!(read_output(&output, &name), both.as_bytes());
+                    }
+                }
+            }
+
+            fs::remove_dir_all(&output.directory).unwrap();
+        }
+    }
+
+    #[test]
+    fn interleaved_output() {
+        let r1 = "@a 1:N:0:AAAAAAAA\nACGT\n+\nIIII\n";
+        let r2 = "@a 2:N:0:AAAAAAAA\nGGGG\n+\nIIII\n";
+
+        let barcodes: SampleSheet = vec![Sample::new("S1", "AAAAAAAA", None)]
+            .into_iter()
+            .collect();
+
+        let output = test_output("deezmux_interleaved_output", OutputFormat::None, true);
+        split_by_barcodes(
+            input("r1.fq", r1),
+            Some(input("r2.fq", r2)),
+            false,
+            &BarcodeMatcher::new(MatchMode::Hamming, barcodes, (1, 1)),
+            None,
+            &output,
+        )
+        .unwrap();
+
+        assert_eq!(
+            read_output(&output, "S1.fq"),
+            format!("{}{}", r1, r2).as_bytes()
+        );
+        assert!(!Path::new(&format!("{}/S1_r1.fq", output.directory)).exists());
+
+        fs::remove_dir_all(&output.directory).unwrap();
     }
 }
@@ -0,0 +1,248 @@
+use crossbeam::channel::{bounded, Receiver, Sender};
+use flate2::bufread::MultiGzDecoder;
+use libdeflater::Decompressor;
+use memchr::memmem;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read};
+use std::thread;
+
+use crate::compression::*;
+
+/// Compressed bytes read at a time
+const READ_SIZE: usize = 1 << 20;
+
+/// Members larger than this, compressed, are inflated as a stream rather than held in memory
+const MAX_MEMBER_SIZE: usize = 1 << 24;
+
+/// Most bytes a member inflated in one go can grow to
+const MAX_MEMBER_OUTPUT: usize = 1 << 28;
+
+/// Header (10 bytes), an empty deflate stream (2) and trailer (8)
+const MIN_MEMBER_SIZE: usize = 20;
+
+/// A member handed back by a worker, with its data or None if it did not inflate
+type Inflated = (Vec<u8>, Option<Vec<u8>>);
+
+/// Inflates a gzip file on `threads` workers with libdeflate, one member at a time. Files
+/// written as many members (concatenated, or by block compressing tools) are inflated in
+/// parallel, and a single member that fits in memory in one libdeflate call.
+///
+/// Members are found by their headers without inflating anything, so compressed data that
+/// happens to look like a header splits a member in two. The first half then fails to
+/// inflate and is joined with the next. Members too large for memory, and data that does
+/// not inflate at all, are left to a streaming decoder, which also reports any error.
+pub fn read_members<R: BufRead>(
+    reader: R,
+    threads: usize,
+    sender: &Sender<Chunk>,
+) -> io::Result<()> {
+    let (jobs, pool) = bounded::<(Vec<u8>, Sender<Inflated>)>(threads);
+    for _ in 0..threads {
+        let pool = pool.clone();
+        thread::spawn(move || {
+            let mut decompressor = Decompressor::new();
+            for (member, result) in pool.iter() {
+                let inflated = inflate_member(&mut decompressor, &member);
+                let _ = result.send((member, inflated));
+            }
+        });
+    }
+
+    let mut members = Members {
+        reader,
+        buffer: Vec::new(),
+        start: 0,
+        searched: 0,
+        eof: false,
+        oversized: false,
+    };
+    let mut pending: VecDeque<Receiver<Inflated>> = VecDeque::new();
+    let mut decompressor = Decompressor::new();
+
+    loop {
+        // Keep every worker busy
+        while pending.len() < threads * 2 {
+            match members.next()? {
+                Some(member) => {
+                    let (send, receive) = bounded(1);
+                    jobs.send((member, send))
+                        .expect("Decompression workers stopped");
+                    pending.push_back(receive);
+                }
+                None => break,
+            }
+        }
+
+        let (mut member, mut inflated) = match pending.pop_front() {
+            Some(oldest) => oldest.recv().expect("Decompression worker stopped"),
+            None if members.oversized => return members.stream(Vec::new(), sender),
+            None => return Ok(()),
+        };
+
+        // What looked like the start of the next member was inside this one
+        while inflated.is_none() && member.len() <= MAX_MEMBER_SIZE {
+            let next = match pending.pop_front() {
+                Some(next) => next.recv().expect("Decompression worker stopped").0,
+                None => match members.next()? {
+                    Some(next) => next,
+                    None => break,
+                },
+            };
+            member.extend_from_slice(&next);
+            inflated = inflate_member(&mut decompressor, &member);
+        }
+
+        match inflated {
+            Some(data) => {
+                if !send_chunk(sender, Ok(data)) {
+                    return Ok(());
+                }
+            }
+            None => {
+                for next in pending.into_iter() {
+                    let next = next.recv().expect("Decompression worker stopped").0;
+                    member.extend_from_slice(&next);
+                }
+                return members.stream(member, sender);
+            }
+        }
+    }
+}
+
+/// Inflates one member with the size and CRC from the end of `member`, None unless they
+/// match: `member` is then not exactly one whole member
+fn inflate_member(decompressor: &mut Decompressor, member: &[u8]) -> Option<Vec<u8>> {
+    let n = member.len();
+    if n < MIN_MEMBER_SIZE {
+        return None;
+    }
+    let crc = u32::from_le_bytes(member[n - 8..n - 4].try_into().unwrap());
+    let size = u32::from_le_bytes(member[n - 4..].try_into().unwrap()) as usize;
+    if size > MAX_MEMBER_OUTPUT {
+        return None;
+    }
+
+    let mut out = vec![0; size];
+    match decompressor.gzip_decompress(member, &mut out) {
+        Ok(len) if len == size && libdeflater::crc32(&out) == crc => Some(out),
+        _ => None,
+    }
+}
+
+/// Splits compressed data before everything that looks like a member header
+struct Members<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    /// Where the next member starts in `buffer`
+    start: usize,
+    /// How far past `start` holds no header
+    searched: usize,
+    eof: bool,
+    /// The next member is larger than `MAX_MEMBER_SIZE`
+    oversized: bool,
+}
+
+impl<R: BufRead> Members<R> {
+    /// The next member, or None at the end of the file or when it is too large to split
+    fn next(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            let data = &self.buffer[self.start..];
+            let from = self.searched.max(MIN_MEMBER_SIZE).min(data.len());
+            let end = match find_header(&data[from..]) {
+                Some(i) => Some(from + i),
+                None if self.eof => Some(data.len()),
+                None if data.len() >= MAX_MEMBER_SIZE => {
+                    self.oversized = true;
+                    return Ok(None);
+                }
+                None => None,
+            };
+
+            if let Some(end) = end {
+                let member = data[..end].to_vec();
+                self.start += end;
+                self.searched = 0;
+                return Ok((!member.is_empty()).then_some(member));
+            }
+
+            // A header cut off by the end of the buffer is looked for again
+            self.searched = data.len().saturating_sub(3);
+            self.buffer.drain(..self.start);
+            self.start = 0;
+
+            let len = self.buffer.len();
+            self.buffer.resize(len + READ_SIZE, 0);
+            let n = loop {
+                match self.reader.read(&mut self.buffer[len..]) {
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    other => break other?,
+                }
+            };
+            self.buffer.truncate(len + n);
+            self.eof = n == 0;
+        }
+    }
+
+    /// Inflates `member` and everything after it as a stream
+    fn stream(self, mut member: Vec<u8>, sender: &Sender<Chunk>) -> io::Result<()> {
+        member.extend_from_slice(&self.buffer[self.start..]);
+        let rest = io::Cursor::new(member).chain(self.reader);
+        read_stream(MultiGzDecoder::new(rest), sender)
+    }
+}
+
+/// The position of the first gzip member header: the magic bytes, deflate, and no reserved
+/// flags. Every real header matches.
+fn find_header(data: &[u8]) -> Option<usize> {
+    memmem::find_iter(data, &[0x1f, 0x8b, 0x08])
+        .find(|i| data.get(i + 3).is_some_and(|flags| flags & 0xe0 == 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn gzip(data: &[u8], level: Compression) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), level);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn decompress(data: Vec<u8>) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        DecompressReader::new(io::Cursor::new(data), 3).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn members_inflate_in_order() {
+        let data: Vec<u8> = (0..500_000).map(|x| b"ACGT\n"[x % 7 % 5]).collect();
+
+        // Stored blocks keep the data as is, so it holds fake headers
+        let fake = b"@r\n\x1f\x8b\x08\x00\x1f\x8b\x08\x04\n".repeat(100);
+        let mut compressed = Vec::new();
+        let mut expected = Vec::new();
+        for (i, chunk) in data.chunks(30_000).enumerate() {
+            let level = match i % 3 {
+                0 => Compression::none(),
+                _ => Compression::fast(),
+            };
+            let chunk = [chunk, &fake].concat();
+            compressed.extend(gzip(&chunk, level));
+            expected.extend(chunk);
+        }
+        assert_eq!(InputFormat::detect(&compressed), InputFormat::Gzip);
+        assert_eq!(decompress(compressed.clone()).unwrap(), expected);
+
+        // A single member
+        assert_eq!(decompress(gzip(&data, Compression::fast())).unwrap(), data);
+
+        // Corruption in a member is reported, not skipped
+        let middle = compressed.len() / 2;
+        compressed[middle] ^= 0xff;
+        assert!(decompress(compressed).is_err());
+    }
+}
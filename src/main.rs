@@ -1,20 +1,34 @@
-use std::ffi::OsStr;
 use std::fs;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Read};
-use std::path::Path;
-use std::sync::Arc;
-
-use clap::{App, AppSettings, Parser, Subcommand};
-use crossbeam::channel::{bounded, Receiver, Sender};
-use crossbeam::thread;
-use flate2::read::MultiGzDecoder;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use clap::{ArgEnum, Args, Parser, Subcommand};
+use indicatif::ProgressBar;
 use indicatif::ProgressStyle;
-use indicatif::{MultiProgress, ProgressBar};
-use wax::{Glob, Pattern};
+use wax::Glob;
 
+mod bam;
+mod bgzf;
+mod compression;
+mod error;
 mod fastq;
+mod gzip;
+mod matcher;
+mod orientation;
+mod samplesheet;
+mod stats;
+mod validate;
+use bam::*;
+use compression::*;
+use error::DeezmuxError;
 use fastq::*;
+use matcher::*;
+use orientation::*;
+use samplesheet::*;
+use stats::*;
+use validate::*;
 
 #[derive(Parser)]
 #[clap(name = "deezmux")]
@@ -22,69 +36,475 @@ use fastq::*;
 #[clap(version = "0.2.0")]
 #[clap(about = "Fast demultiplexing of Illumina files where the barcode IDs are in the header", long_about = None)]
 struct Cli {
-    barcode_file: String,
-    output_directory: String,
-    read_prefix: String,
+    #[clap(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Split single-end, paired or interleaved reads into files per sample: one per read, or
+    /// one holding both mates with --interleaved-output or --output-format bam
+    Split {
+        barcode_file: String,
+        output_directory: String,
+
+        #[clap(flatten)]
+        input: InputArgs,
+
+        #[clap(flatten)]
+        matching: MatchArgs,
+
+        #[clap(flatten)]
+        orientation: OrientationArgs,
+
+        /// Split even if samples in the barcode file collide at the allowed mismatches
+        #[clap(long)]
+        skip_validation: bool,
+
+        #[clap(flatten)]
+        output: OutputArgs,
+    },
+    /// Count reads per sample from the barcodes alone, without writing any reads
+    Count {
+        barcode_file: String,
+
+        #[clap(flatten)]
+        input: InputArgs,
+
+        #[clap(flatten)]
+        matching: MatchArgs,
+
+        #[clap(flatten)]
+        orientation: OrientationArgs,
+
+        /// Number of undetermined barcodes to list
+        #[clap(long, default_value_t = 20)]
+        top_undetermined: usize,
+    },
+    /// Check the barcode file for samples that can not be told apart
+    Validate {
+        barcode_file: String,
+
+        #[clap(flatten)]
+        matching: MatchArgs,
+    },
+}
+
+#[derive(Args)]
+struct InputArgs {
+    /// Finds the read files starting with this prefix, ie: runs/lib_ for runs/lib_R1.fastq.gz
+    read_prefix: Option<String>,
+
+    /// R1 files, multiple files are read one after the other. - reads standard input
+    #[clap(long, multiple_values = true)]
+    r1: Vec<PathBuf>,
+
+    /// R2 files, in the same order as R1
+    #[clap(long, multiple_values = true)]
+    r2: Vec<PathBuf>,
+
+    /// I1 files, when the barcodes are not in the read headers
+    #[clap(long, multiple_values = true)]
+    i1: Vec<PathBuf>,
+
+    /// I2 files, for dual-index runs
+    #[clap(long, multiple_values = true)]
+    i2: Vec<PathBuf>,
+
+    /// R1 holds both mates, alternating R1 and R2 records
+    #[clap(long)]
+    interleaved: bool,
+
+    /// Threads decompressing each gzip or BGZF input file (other files get one)
+    #[clap(long, default_value_t = 4)]
+    decompression_threads: usize,
+}
+
+#[derive(Args)]
+struct MatchArgs {
+    /// How observed barcodes are compared to the sample sheet
+    #[clap(long, arg_enum, default_value = "levenshtein")]
+    match_mode: MatchMode,
+
+    /// Mismatches allowed in the i7 index
+    #[clap(long, default_value_t = 1)]
+    mismatches_i7: u32,
+
+    /// Mismatches allowed in the i5 index
+    #[clap(long, default_value_t = 1)]
+    mismatches_i5: u32,
+}
+
+#[derive(Args)]
+struct OrientationArgs {
+    /// Orientation of i5 in the reads relative to the sample sheet
+    #[clap(long, arg_enum, default_value = "forward")]
+    i5_orientation: I5Orientation,
+
+    /// Number of reads used to detect the orientation with --i5-orientation auto
+    #[clap(long, default_value_t = 100000)]
+    orientation_sample_size: usize,
+}
+
+#[derive(Args)]
+struct OutputArgs {
+    /// Write reads whose i7 and i5 match different samples to HOPPED instead of UNASSIGNED
+    #[clap(long)]
+    hopped_output: bool,
+
+    /// Write both mates to one file per sample, alternating R1 and R2 records
+    #[clap(long)]
+    interleaved_output: bool,
+
+    /// Keep one set of output files per lane instead of merging lanes
+    #[clap(long)]
+    per_lane: bool,
+
+    /// Number of barcodes to list in undetermined_barcodes.tsv
+    #[clap(long, default_value_t = 100)]
+    top_undetermined: usize,
+
+    /// Time the split and report its throughput
+    #[clap(long)]
+    benchmark: bool,
+
+    /// Threads compressing the output files
+    #[clap(long, default_value_t = default_threads())]
+    threads: usize,
+
+    /// Compression of the output files, or unaligned BAM with read groups from the sample
+    /// sheet and barcodes in BC/QT tags
+    #[clap(long, arg_enum, default_value = "gz")]
+    output_format: OutputFormat,
+
+    /// Compression level, 1 for gz and bam and 3 for zst when not given
+    #[clap(long)]
+    compression_level: Option<i32>,
 }
 
-enum Mode {
-    BarcodesInHeader,
-    BarcodesInSeparateFile,
+fn default_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |x| x.get())
 }
 
-fn parse_barcode_file(barcode_file: &str) -> Vec<(String, String, String, String, String)> {
-    let file = File::open(barcode_file).expect("Unable to open barcode file");
-    let bufread = BufReader::new(file);
+impl MatchArgs {
+    fn mismatches(&self) -> (u32, u32) {
+        (self.mismatches_i7, self.mismatches_i5)
+    }
+}
+
+fn parse_barcode_file(barcode_file: &str) -> Result<SampleSheet, DeezmuxError> {
+    let contents = fs::read_to_string(barcode_file).map_err(DeezmuxError::io(format!(
+        "Unable to open barcode file {}",
+        barcode_file
+    )))?;
 
-    let mut barcodes: Vec<(String, String, String, String, String)> = Vec::new();
+    let samples = if is_illumina_sample_sheet(&contents) {
+        let sheet = parse_illumina_sample_sheet(&contents)?;
+        println!(
+            "Found Illumina sample sheet (v{}) with {} samples",
+            sheet.version,
+            sheet.samples.len()
+        );
+        sheet.samples
+    } else {
+        parse_barcode_csv(&contents)?
+    };
 
-    for line in bufread.lines().skip(1) {
-        let line = line.unwrap();
-        let j: Vec<&str> = line.split(",").collect();
-        let z: Vec<&str> = j[1].split("+").collect();
-        barcodes.push((
-            z[0].to_string(), // Barcode 0
-            z[1].to_string(), // Barcode 1
-            j[0].to_string(), // Sample ID
-            j[2].to_string(), // Barcode 0 sequence
-            j[3].to_string(), // Barcode 1 sequence
+    if samples.is_empty() {
+        return Err(DeezmuxError::SampleSheet(
+            "No samples found in barcode file".to_string(),
         ));
     }
 
-    barcodes
+    Ok(samples)
 }
 
 fn main() {
     let args = Cli::parse();
 
-    /* let barcode_file = match matches.value_of("barcode_file") {
-        Some(x) => x,
-        None => panic!("No barcode file specified"),
+    let result = match args.command {
+        Commands::Split {
+            barcode_file,
+            output_directory,
+            input,
+            matching,
+            orientation,
+            skip_validation,
+            output,
+        } => split(
+            &barcode_file,
+            &output_directory,
+            &input,
+            &matching,
+            &orientation,
+            skip_validation,
+            &output,
+        ),
+        Commands::Count {
+            barcode_file,
+            input,
+            matching,
+            orientation,
+            top_undetermined,
+        } => count(
+            &barcode_file,
+            &input,
+            &matching,
+            &orientation,
+            top_undetermined,
+        ),
+        Commands::Validate {
+            barcode_file,
+            matching,
+        } => validate(&barcode_file, &matching),
     };
 
-    let files = match matches.values_of("read_files") {
-        Some(x) => x,
-        None => panic!("No read files specified"),
-    }; */
-    let barcode_file = &args.barcode_file;
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
 
+fn validate(barcode_file: &str, matching: &MatchArgs) -> Result<(), DeezmuxError> {
     println!("Parsing barcode file: {}", barcode_file);
-    let barcodes = Arc::new(parse_barcode_file(barcode_file));
+    let barcodes = parse_barcode_file(barcode_file)?;
+
+    let validation = validate_barcodes(&barcodes, matching.match_mode, matching.mismatches());
+    validation.report();
+
+    if !validation.is_ok() {
+        return Err(DeezmuxError::SampleSheet(format!(
+            "{} conflicting sample pairs",
+            validation.conflicts.len()
+        )));
+    }
+
+    println!("No conflicts found between {} samples", barcodes.len());
+    Ok(())
+}
 
-    let output_directory = &args.output_directory;
-    let prefix = &args.read_prefix;
+/// Finds the one file among `files` with `tag` (ie: _R1) in its name
+fn find_file<'a>(files: &'a [PathBuf], tag: &str) -> Result<Option<&'a PathBuf>, DeezmuxError> {
+    let mut found = files.iter().filter(|x| {
+        x.file_name()
+            .and_then(|x| x.to_str())
+            .is_some_and(|x| x.contains(tag))
+    });
+
+    match (found.next(), found.next()) {
+        (Some(_), Some(_)) => Err(DeezmuxError::InputDiscovery(format!(
+            "More than one file matching {}",
+            tag
+        ))),
+        (x, _) => Ok(x),
+    }
+}
+
+/// "1 file", "2 files"...
+fn file_count(n: usize) -> String {
+    match n {
+        1 => "1 file".to_string(),
+        n => format!("{} files", n),
+    }
+}
 
+/// The lane from an Illumina file name, ie: 1 for sample_S1_L001_R1_001.fastq.gz. The last
+/// `_L<3 digits>_` is the lane, as the sample name before it can look like one too.
+fn file_lane(path: &Path) -> Option<u32> {
+    let name = path.file_name()?.to_str()?;
+
+    name.rmatch_indices("_L").find_map(|(i, _)| {
+        let digits = name.get(i + 2..i + 5)?;
+        match digits.bytes().all(|b| b.is_ascii_digit()) && name[i + 5..].starts_with('_') {
+            true => digits.parse().ok(),
+            false => None,
+        }
+    })
+}
+
+/// Read files, in the order they are read. Multi-lane runs have one file per lane for each read.
+#[derive(Default)]
+struct Inputs {
+    r1: Vec<PathBuf>,
+    r2: Vec<PathBuf>,
+    /// Empty when the barcodes are in the headers
+    i1: Vec<PathBuf>,
+    i2: Vec<PathBuf>,
+    /// Lane of each R1 file, None when the file name doesn't say
+    lanes: Vec<Option<u32>>,
+    /// Threads inflating each file
+    threads: usize,
+    /// R1 holds both mates
+    interleaved: bool,
+}
+
+impl Inputs {
+    /// The files of each lane, in the order lanes first appear
+    fn lane_groups(&self) -> Vec<(Option<u32>, Inputs)> {
+        let mut groups: Vec<(Option<u32>, Inputs)> = Vec::new();
+
+        for (i, lane) in self.lanes.iter().enumerate() {
+            let group = match groups.iter().position(|x| x.0 == *lane) {
+                Some(group) => group,
+                None => {
+                    groups.push((
+                        *lane,
+                        Inputs {
+                            threads: self.threads,
+                            interleaved: self.interleaved,
+                            ..Default::default()
+                        },
+                    ));
+                    groups.len() - 1
+                }
+            };
+
+            let inputs = &mut groups[group].1;
+            inputs.r1.push(self.r1[i].clone());
+            inputs.r2.extend(self.r2.get(i).cloned());
+            inputs.i1.extend(self.i1.get(i).cloned());
+            inputs.i2.extend(self.i2.get(i).cloned());
+            inputs.lanes.push(*lane);
+        }
+
+        groups
+    }
+
+    /// I1 and optionally I2, None when the barcodes are in the headers
+    fn index_files(&self) -> Option<(&[PathBuf], Option<&[PathBuf]>)> {
+        match (self.i1.is_empty(), self.i2.is_empty()) {
+            (true, _) => None,
+            (false, true) => Some((&self.i1, None)),
+            (false, false) => Some((&self.i1, Some(&self.i2))),
+        }
+    }
+
+    /// True if any of the files is standard input, which can only be read once
+    fn reads_stdin(&self) -> bool {
+        [&self.r1, &self.r2, &self.i1, &self.i2]
+            .iter()
+            .flat_map(|x| x.iter())
+            .any(|x| is_stdin(x))
+    }
+
+    /// Flowcell and lane from the first R1 read name, None when it has neither or R1 is
+    /// standard input, which can only be read once
+    fn platform_unit(&self) -> Result<Option<PlatformUnit>, DeezmuxError> {
+        if self.r1.iter().any(|x| is_stdin(x)) {
+            return Ok(None);
+        }
+        let header = first_header(open_input_files(&self.r1, 1)?)?;
+        Ok(header.and_then(|x| PlatformUnit::from_header(&x)))
+    }
+
+    /// Opens the I1 (and I2) files, None when the barcodes are in the headers
+    fn open_index_files(&self) -> Result<Option<(InputFiles, Option<InputFiles>)>, DeezmuxError> {
+        match self.index_files() {
+            Some((i1, i2)) => Ok(Some((
+                open_input_files(i1, self.threads)?,
+                i2.map(|x| open_input_files(x, self.threads)).transpose()?,
+            ))),
+            None => Ok(None),
+        }
+    }
+}
+
+impl InputArgs {
+    /// The read files given explicitly, or found from the prefix
+    fn inputs(&self) -> Result<Inputs, DeezmuxError> {
+        let explicit =
+            !(self.r1.is_empty() && self.r2.is_empty() && self.i1.is_empty() && self.i2.is_empty());
+
+        let mut inputs = match (&self.read_prefix, explicit) {
+            (Some(prefix), false) => find_inputs(prefix, self.interleaved)?,
+            (None, true) => Inputs {
+                r1: self.r1.clone(),
+                r2: self.r2.clone(),
+                i1: self.i1.clone(),
+                i2: self.i2.clone(),
+                lanes: self.r1.iter().map(|x| file_lane(x)).collect(),
+                ..Default::default()
+            },
+            (Some(_), true) => {
+                return Err(DeezmuxError::InputDiscovery(
+                    "Give either a read prefix or --r1/--r2, not both".to_string(),
+                ))
+            }
+            (None, false) => {
+                return Err(DeezmuxError::InputDiscovery(
+                    "Give a read prefix or --r1 and --r2".to_string(),
+                ))
+            }
+        };
+
+        inputs.threads = self.decompression_threads;
+        inputs.interleaved = self.interleaved;
+
+        if inputs.r1.is_empty() {
+            return Err(DeezmuxError::InputDiscovery(
+                "No R1 files given".to_string(),
+            ));
+        }
+        if inputs.interleaved && !inputs.r2.is_empty() {
+            return Err(DeezmuxError::InputDiscovery(
+                "Interleaved R1 files already hold R2, no R2 files are needed".to_string(),
+            ));
+        }
+        let stdin = [&inputs.r1, &inputs.r2, &inputs.i1, &inputs.i2]
+            .iter()
+            .flat_map(|x| x.iter())
+            .filter(|x| is_stdin(x))
+            .count();
+        if stdin > 1 {
+            return Err(DeezmuxError::InputDiscovery(
+                "Only one file can be read from standard input (-)".to_string(),
+            ));
+        }
+        if inputs.i1.is_empty() && !inputs.i2.is_empty() {
+            return Err(DeezmuxError::InputDiscovery(
+                "I2 files given without I1 files".to_string(),
+            ));
+        }
+
+        // Files are paired up in the order given, so every read needs the same number
+        let n = inputs.r1.len();
+        for (name, files) in [("R2", &inputs.r2), ("I1", &inputs.i1), ("I2", &inputs.i2)] {
+            if !files.is_empty() && files.len() != n {
+                return Err(DeezmuxError::InputDiscovery(format!(
+                    "{} R1 files but {} {} files",
+                    n,
+                    files.len(),
+                    name
+                )));
+            }
+        }
+
+        Ok(inputs)
+    }
+}
+
+fn find_inputs(prefix: &str, interleaved: bool) -> Result<Inputs, DeezmuxError> {
     // Figure out what type of files we are dealing with
 
-    let prefix_directory = Path::new(prefix)
-        .parent()
-        .expect("Unable to determine parent directory of prefix path")
+    let prefix_path = Path::new(prefix);
+    let prefix_directory = match prefix_path.parent() {
+        Some(x) if !x.as_os_str().is_empty() => x,
+        _ => Path::new("."),
+    };
+    let prefix_directory = prefix_directory
         .canonicalize()
-        .expect("Unable to canonicalize prefix path");
-    let prefix_files = Path::new(prefix).file_name().unwrap().to_str().unwrap();
-    let glob = format!("{}*.gz", prefix_files).to_string();
+        .map_err(DeezmuxError::io(format!(
+            "Unable to find directory {}",
+            prefix_directory.display()
+        )))?;
+    let prefix_files = prefix_path
+        .file_name()
+        .and_then(|x| x.to_str())
+        .ok_or_else(|| DeezmuxError::InputDiscovery(format!("Invalid prefix {}", prefix)))?;
+    let glob = format!("{}*.{{gz,zst,bz2,fastq,fq}}", prefix_files);
 
-    let glob = Glob::new(&glob).expect("Unable to create glob");
+    let glob = Glob::new(&glob)
+        .map_err(|e| DeezmuxError::InputDiscovery(format!("Invalid prefix {}: {}", prefix, e)))?;
     let mut files = Vec::with_capacity(4);
 
     for i in glob.walk(prefix_directory, 1) {
@@ -98,105 +518,558 @@ fn main() {
         }
     }
 
-    let mode = match files.len() {
-        0 => panic!("No files found matching prefix provided"),
-        1 => {
-            panic!("Only one file found matching prefix. Expected paired reads")
-        }
-        2 => {
-            println!("Found two files matching prefix. Assuming paired reads and barcode found in header");
-            Mode::BarcodesInHeader
+    if files.is_empty() {
+        return Err(DeezmuxError::InputDiscovery(
+            "No files found matching prefix provided".to_string(),
+        ));
+    }
+
+    // Multi-lane runs have one set of files per lane (_L001, _L002...)
+    let mut lanes: Vec<(Option<u32>, Vec<PathBuf>)> = Vec::new();
+    for file in files {
+        let lane = file_lane(&file);
+        match lanes.iter_mut().find(|x| x.0 == lane) {
+            Some((_, files)) => files.push(file),
+            None => lanes.push((lane, vec![file])),
         }
-        4 => {
-            println!(
-                "Found four files matching prefix. Assuming paired reads and barcode in I files"
-            );
-            Mode::BarcodesInSeparateFile
+    }
+    lanes.sort_by_key(|x| x.0);
+
+    if lanes.len() > 1 {
+        println!("Found {} lanes", lanes.len());
+    }
+
+    let files_per_lane = lanes[0].1.len();
+    if let Some((lane, files)) = lanes.iter().find(|x| x.1.len() != files_per_lane) {
+        return Err(DeezmuxError::InputDiscovery(format!(
+            "Found {} for lane {} but {} for lane {}",
+            file_count(files.len()),
+            lane.unwrap_or(0),
+            files_per_lane,
+            lanes[0].0.unwrap_or(0)
+        )));
+    }
+
+    let missing = |tag: &str| DeezmuxError::InputDiscovery(format!("No {} file found", tag));
+
+    let mut inputs = Inputs::default();
+
+    for (lane, files) in lanes.iter() {
+        let r1 = find_file(files, "_R1")?.ok_or_else(|| missing("_R1"))?;
+        let r2 = find_file(files, "_R2")?;
+        let i1 = find_file(files, "_I1")?;
+        let i2 = find_file(files, "_I2")?;
+
+        if i2.is_some() && i1.is_none() {
+            return Err(missing("_I1"));
         }
-        _ => {
-            panic!("Found unusual number of files matching prefix. Expected paired reads")
+
+        let found = 1 + [r2, i1, i2].iter().filter(|x| x.is_some()).count();
+        if found != files.len() {
+            return Err(DeezmuxError::InputDiscovery(format!(
+                "Found {} matching prefix, but only {} of them are R1, R2, I1 or I2",
+                file_count(files.len()),
+                found
+            )));
         }
+
+        inputs.r1.push(r1.clone());
+        inputs.r2.extend(r2.cloned());
+        inputs.i1.extend(i1.cloned());
+        inputs.i2.extend(i2.cloned());
+        inputs.lanes.push(*lane);
+    }
+
+    let reads = match (inputs.r2.is_empty(), interleaved) {
+        (true, true) => "interleaved paired reads",
+        (true, false) => "single-end reads",
+        (false, _) => "paired reads",
     };
+    let barcodes = match (inputs.i1.is_empty(), inputs.i2.is_empty()) {
+        (true, _) => "barcode found in header",
+        (false, true) => "a single barcode in the I1 file",
+        (false, false) => "barcode in I files",
+    };
+    println!(
+        "Found {} matching prefix. Assuming {} and {}",
+        file_count(files_per_lane * lanes.len()),
+        reads,
+        barcodes
+    );
 
-    let index_files = match mode {
-        Mode::BarcodesInHeader => None,
-        Mode::BarcodesInSeparateFile => Some((
-            files
-                .iter()
-                .find(|&x| x.file_name().unwrap().to_str().unwrap().contains("_I1"))
-                .unwrap(),
-            files
-                .iter()
-                .find(|&x| x.file_name().unwrap().to_str().unwrap().contains("_I2"))
-                .unwrap(),
+    Ok(inputs)
+}
+
+/// The sample sheet with its barcodes rewritten to how they appear in the reads
+fn orient_samples(
+    barcodes: &SampleSheet,
+    inputs: &Inputs,
+    matching: &MatchArgs,
+    orientation: &OrientationArgs,
+) -> Result<SampleSheet, DeezmuxError> {
+    let layout = match orientation.i5_orientation {
+        I5Orientation::Forward => IndexLayout::Forward,
+        I5Orientation::Revcomp => IndexLayout::RevcompI5,
+        I5Orientation::Auto if inputs.reads_stdin() => return Err(DeezmuxError::Arguments(
+            "--i5-orientation auto reads the input twice, which standard input does not allow. \
+                 Give forward or revcomp"
+                .to_string(),
         )),
+        I5Orientation::Auto => {
+            let n = orientation.orientation_sample_size;
+            println!("Detecting index orientation from the first {} reads", n);
+
+            let sampled = match inputs.open_index_files()? {
+                Some((i1, i2)) => first_index_barcodes(i1, i2, n)?,
+                None => first_header_barcodes(open_input_files(&inputs.r1, inputs.threads)?, n)?,
+            };
+
+            detect_layout(
+                barcodes,
+                matching.match_mode,
+                matching.mismatches(),
+                &sampled,
+            )
+        }
     };
 
-    let files = [
-        files
-            .iter()
-            .find(|&x| x.file_name().unwrap().to_str().unwrap().contains("_R1"))
-            .unwrap(),
-        files
+    if layout != IndexLayout::Forward {
+        println!("Using index layout {:?}", layout);
+    }
+
+    Ok(layout.apply(barcodes))
+}
+
+/// Opens the files of one read, with a progress bar following how much of them has been
+/// read
+fn open_with_progress(
+    paths: &[PathBuf],
+    threads: usize,
+) -> Result<(ProgressBar, InputFiles), DeezmuxError> {
+    let mut files: Vec<(String, Box<dyn Read + Send>)> = Vec::with_capacity(paths.len());
+    let mut len = 0;
+    for path in paths.iter() {
+        // Standard input has no size, so the bar only covers the files
+        if is_stdin(path) {
+            files.push((input_name(path), Box::new(std::io::stdin())));
+            continue;
+        }
+
+        let fh = File::open(path).map_err(DeezmuxError::io(format!(
+            "Unable to open {}",
+            path.display()
+        )))?;
+        len += fh.metadata().map(|x| x.len()).unwrap_or(0);
+        files.push((input_name(path), Box::new(fh)));
+    }
+
+    let pb = ProgressBar::new(len);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.blue}▕{bar:.green}▏{bytes:>4}/{total_bytes:4} {eta}")
+            // .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {eta}"),
+            .progress_chars("█▇▆▅▄▃▂▁  "),
+    );
+
+    let files = files
+        .into_iter()
+        .map(|(name, fh)| {
+            let reader: InputReader = Box::new(DecompressReader::new(pb.wrap_read(fh), threads));
+            (name, reader)
+        })
+        .collect();
+    Ok((pb, files))
+}
+
+fn split(
+    barcode_file: &str,
+    output_directory: &str,
+    input: &InputArgs,
+    matching: &MatchArgs,
+    orientation: &OrientationArgs,
+    skip_validation: bool,
+    output: &OutputArgs,
+) -> Result<(), DeezmuxError> {
+    println!("Parsing barcode file: {}", barcode_file);
+    let barcodes = parse_barcode_file(barcode_file)?;
+
+    // Catch ambiguous sheets before spending hours splitting into AMBIGUOUS
+    if !skip_validation {
+        let validation = validate_barcodes(&barcodes, matching.match_mode, matching.mismatches());
+        if !validation.is_ok() {
+            validation.report();
+            return Err(DeezmuxError::SampleSheet(
+                "Conflicting samples, lower the allowed mismatches or pass --skip-validation"
+                    .to_string(),
+            ));
+        }
+    }
+
+    let format = output.output_format;
+    let level = output
+        .compression_level
+        .unwrap_or_else(|| format.default_level());
+    if !format.levels().contains(&level) {
+        return Err(DeezmuxError::Arguments(format!(
+            "Compression level {} is outside {:?} for {:?} output",
+            level,
+            format.levels(),
+            format
+        )));
+    }
+
+    let inputs = input.inputs()?;
+    if output.interleaved_output && inputs.r2.is_empty() && !inputs.interleaved {
+        return Err(DeezmuxError::Arguments(
+            "--interleaved-output needs paired reads".to_string(),
+        ));
+    }
+    if output.interleaved_output && format == OutputFormat::Bam {
+        return Err(DeezmuxError::Arguments(
+            "--interleaved-output is for FASTQ, BAM always holds both mates".to_string(),
+        ));
+    }
+
+    let samples = orient_samples(&barcodes, &inputs, matching, orientation)?;
+
+    let start = Instant::now();
+
+    // Lanes are split one after the other, each only looking for the samples expected in it
+    let mut lane_stats = Vec::new();
+    let mut written: Vec<String> = Vec::new();
+    let lane_groups = inputs.lane_groups();
+
+    // BAM read groups come from the first read name of each lane
+    let units = match format {
+        OutputFormat::Bam => lane_groups
             .iter()
-            .find(|&x| x.file_name().unwrap().to_str().unwrap().contains("_R2"))
-            .unwrap(),
-    ];
-
-    // let fqs = FastqSplitter::new().with_mm(2, 2);
-
-    thread::scope(|s| {
-        let m = MultiProgress::new();
-        let mut t = Vec::new();
-
-        for (i, file) in files.iter().enumerate() {
-            let r = format!("r{}", i + 1);
-            // println!("Processing {} as {}", file, r);
-
-            /*
-            let file_pb = File::open(file).expect("Unable to open file");
-            let pb = ProgressBar::new(file_pb.metadata().unwrap().len());
-            pb.set_style(
-                ProgressStyle::default_bar()
-                    .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {eta}"),
-            );
-            let barcodes =
-                fqs.match_barcodes(MultiGzDecoder::new(BufReader::new(pb.wrap_read(&file_pb))));
-
-            */
-
-            let file_pb = File::open(file).expect("Unable to open file");
-            let pb = ProgressBar::new(file_pb.metadata().unwrap().len());
-            pb.set_style(
-                ProgressStyle::default_bar()
-                    .template("{spinner:.blue}▕{bar:.green}▏{bytes:>4}/{total_bytes:4} {eta}")
-                    // .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {eta}"),
-                    .progress_chars("█▇▆▅▄▃▂▁  "),
+            .map(|x| x.1.platform_unit())
+            .collect::<Result<Vec<_>, DeezmuxError>>()?,
+        _ => vec![None; lane_groups.len()],
+    };
+    let mut merged_units: Vec<Option<PlatformUnit>> = Vec::new();
+    for unit in units.iter() {
+        if !merged_units.contains(unit) {
+            merged_units.push(unit.clone());
+        }
+    }
+
+    for ((lane, inputs), unit) in lane_groups.iter().zip(units) {
+        if let Some(lane) = lane {
+            println!("Lane {}", lane);
+        }
+
+        let matcher = BarcodeMatcher::new(
+            matching.match_mode,
+            samples.for_lane(*lane),
+            matching.mismatches(),
+        );
+
+        let split_output = SplitOutput {
+            directory: output_directory.to_string(),
+            suffix: match (output.per_lane, lane) {
+                (true, Some(lane)) => format!("_L{:03}", lane),
+                _ => String::new(),
+            },
+            // Merged lanes are appended as extra gzip members, zstd frames or BAM records
+            append_to: written.clone(),
+            hopped: output.hopped_output,
+            interleaved: output.interleaved_output,
+            threads: output.threads,
+            format: output.output_format,
+            level,
+            read_groups: match output.per_lane {
+                true => vec![unit.clone()],
+                false => merged_units.clone(),
+            },
+            read_group: unit,
+        };
+
+        // Progress is tracked on R1 only, R2 is read in lockstep with it
+        let (pb, r1) = open_with_progress(&inputs.r1, inputs.threads)?;
+        let r2 = match inputs.r2.is_empty() {
+            true => None,
+            false => Some(open_input_files(&inputs.r2, inputs.threads)?),
+        };
+        let index = inputs.open_index_files()?;
+
+        let mut stats =
+            split_by_barcodes(r1, r2, inputs.interleaved, &matcher, index, &split_output)?;
+        stats.lane = *lane;
+
+        if !output.per_lane {
+            written.extend(
+                output_names(&matcher, output.hopped_output)
+                    .into_iter()
+                    .map(|x| x.1),
             );
+        }
+
+        pb.finish();
+        lane_stats.push(stats);
+    }
 
-            let file_fh = pb.wrap_read(file_pb);
+    let stats = DemuxStats::merge_lanes(lane_stats);
 
-            let _pb = m.add(pb);
+    stats.print_summary();
+    if output.benchmark {
+        print_benchmark(
+            &inputs,
+            output,
+            stats.total_reads,
+            start.elapsed().as_secs_f64(),
+        );
+    }
+    stats.write(output_directory)?;
+
+    let undetermined = stats.top_undetermined(
+        &samples,
+        matching.match_mode,
+        matching.mismatches(),
+        output.top_undetermined,
+    );
+    write_undetermined(output_directory, &undetermined)
+}
 
-            let barcodes = Arc::clone(&barcodes);
+/// Reads per second, and compressed megabytes per second over every input file, with the
+/// input and output layout that was timed
+fn print_benchmark(inputs: &Inputs, output: &OutputArgs, reads: usize, seconds: f64) {
+    let bytes: u64 = [&inputs.r1, &inputs.r2, &inputs.i1, &inputs.i2]
+        .iter()
+        .flat_map(|x| x.iter())
+        .map(|x| fs::metadata(x).map(|x| x.len()).unwrap_or(0))
+        .sum();
+    let seconds = seconds.max(f64::EPSILON);
 
-            let handle = s.spawn(move |_| {
-                split_by_barcodes(
-                    MultiGzDecoder::new(BufReader::new(file_fh)),
-                    r,
-                    output_directory.to_string(),
-                    barcodes,
-                    index_files,
-                );
-            });
-            t.push(handle);
+    let layout = match (inputs.interleaved, inputs.r2.is_empty()) {
+        (true, _) => "interleaved",
+        (false, true) => "single-end",
+        (false, false) => "paired",
+    };
+    let format = output
+        .output_format
+        .to_possible_value()
+        .map_or("", |x| x.get_name());
+    let interleaved = match output.interleaved_output {
+        true => "interleaved ",
+        false => "",
+    };
+
+    println!(
+        "Split {} {} reads to {}{} output in {:.2}s: {:.0} reads/s, {:.1} MB/s of compressed input",
+        reads,
+        layout,
+        interleaved,
+        format,
+        seconds,
+        reads as f64 / seconds,
+        bytes as f64 / 1e6 / seconds
+    );
+}
+
+fn count(
+    barcode_file: &str,
+    input: &InputArgs,
+    matching: &MatchArgs,
+    orientation: &OrientationArgs,
+    top_undetermined: usize,
+) -> Result<(), DeezmuxError> {
+    println!("Parsing barcode file: {}", barcode_file);
+    let barcodes = parse_barcode_file(barcode_file)?;
+
+    let inputs = input.inputs()?;
+    let samples = orient_samples(&barcodes, &inputs, matching, orientation)?;
+
+    let (mm1, mm2) = matching.mismatches();
+
+    let mut lane_stats = Vec::new();
+    for (lane, inputs) in inputs.lane_groups().iter() {
+        let fqs = FastqSplitter::new()
+            .with_mm(mm1, mm2)
+            .with_match_mode(matching.match_mode)
+            .with_barcodes(samples.for_lane(*lane))
+            .with_interleaved(inputs.interleaved);
+
+        // Only the file holding the barcodes is read
+        let mut stats = match inputs.index_files() {
+            Some((i1, i2)) => {
+                let (pb, i1) = open_with_progress(i1, inputs.threads)?;
+                let i2 = i2
+                    .map(|x| open_input_files(x, inputs.threads))
+                    .transpose()?;
+                let stats = fqs.match_index_barcodes(i1, i2)?;
+                pb.finish();
+                stats
+            }
+            None => {
+                let (pb, r1) = open_with_progress(&inputs.r1, inputs.threads)?;
+                let stats = fqs.match_header_barcodes(r1)?;
+                pb.finish();
+                stats
+            }
+        };
+        stats.lane = *lane;
+        lane_stats.push(stats);
+    }
+
+    let stats = DemuxStats::merge_lanes(lane_stats);
+
+    stats.print_samples();
+    stats.print_summary();
+
+    let undetermined = stats.top_undetermined(
+        &samples,
+        matching.match_mode,
+        matching.mismatches(),
+        top_undetermined,
+    );
+    if !undetermined.is_empty() {
+        println!();
+        print!("{}", undetermined_tsv(&undetermined));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lane_from_file_name() {
+        let lane = |name: &str| file_lane(Path::new(name));
+        assert_eq!(lane("sample_S1_L001_R1_001.fastq.gz"), Some(1));
+        assert_eq!(lane("runs/sample_S1_L004_I2_001.fastq.gz"), Some(4));
+        assert_eq!(lane("Lib_L123_S1_L001_R1_001.fastq.gz"), Some(1));
+        assert_eq!(lane("Lib_L123_R1.fastq.gz"), Some(123));
+        assert_eq!(lane("sample_L01_R1.fastq.gz"), None);
+        assert_eq!(lane("sample_L001.fastq.gz"), None);
+        assert_eq!(lane("sample_R1.fastq.gz"), None);
+    }
+
+    #[test]
+    fn counts_files() {
+        assert_eq!(file_count(1), "1 file");
+        assert_eq!(file_count(4), "4 files");
+    }
+
+    #[test]
+    fn lane_groups_in_first_seen_order() {
+        let paths = |names: &[&str]| names.iter().map(PathBuf::from).collect::<Vec<_>>();
+        let inputs = Inputs {
+            r1: paths(&["a_L002_R1", "a_L001_R1", "b_L002_R1"]),
+            r2: paths(&["a_L002_R2", "a_L001_R2", "b_L002_R2"]),
+            lanes: vec![Some(2), Some(1), Some(2)],
+            threads: 3,
+            ..Default::default()
+        };
+
+        let groups = inputs.lane_groups();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].0, Some(2));
+        assert_eq!(groups[0].1.r1, paths(&["a_L002_R1", "b_L002_R1"]));
+        assert_eq!(groups[0].1.r2, paths(&["a_L002_R2", "b_L002_R2"]));
+        assert_eq!(groups[0].1.threads, 3);
+        assert_eq!(groups[1].0, Some(1));
+        assert_eq!(groups[1].1.r1, paths(&["a_L001_R1"]));
+        assert!(groups[1].1.i1.is_empty());
+    }
+
+    #[test]
+    fn find_inputs_by_lane() {
+        let directory = std::env::temp_dir().join("deezmux_find_inputs");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let touch = |name: &str| File::create(directory.join(name)).unwrap();
+        for lane in ["L002", "L001"] {
+            for read in ["R1", "R2", "I1"] {
+                touch(&format!("Lib_L123_S1_{}_{}_001.fastq.gz", lane, read));
+            }
         }
+        let prefix = directory.join("Lib_L123_S1_");
+        let prefix = prefix.to_str().unwrap();
+
+        let inputs = find_inputs(prefix, false).unwrap();
+        assert_eq!(inputs.lanes, vec![Some(1), Some(2)]);
+        let names = |files: &[PathBuf]| {
+            files
+                .iter()
+                .map(|x| x.file_name().unwrap().to_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(&inputs.r1),
+            [
+                "Lib_L123_S1_L001_R1_001.fastq.gz",
+                "Lib_L123_S1_L002_R1_001.fastq.gz"
+            ]
+        );
+        assert_eq!(
+            names(&inputs.i1),
+            [
+                "Lib_L123_S1_L001_I1_001.fastq.gz",
+                "Lib_L123_S1_L002_I1_001.fastq.gz"
+            ]
+        );
+        assert!(inputs.i2.is_empty());
 
-        m.join().unwrap();
+        // Every lane needs the same files
+        fs::remove_file(directory.join("Lib_L123_S1_L002_I1_001.fastq.gz")).unwrap();
+        assert!(matches!(
+            find_inputs(prefix, false),
+            Err(DeezmuxError::InputDiscovery(_))
+        ));
+    }
 
-        for i in t {
-            i.join().unwrap();
+    fn input_args(r1: &[&str], r2: &[&str], i1: &[&str], i2: &[&str]) -> InputArgs {
+        let paths = |names: &[&str]| names.iter().map(PathBuf::from).collect();
+        InputArgs {
+            read_prefix: None,
+            r1: paths(r1),
+            r2: paths(r2),
+            i1: paths(i1),
+            i2: paths(i2),
+            interleaved: false,
+            decompression_threads: 2,
         }
-    })
-    .expect("Unable to properly scope");
+    }
+
+    #[test]
+    fn explicit_inputs() {
+        let args = input_args(&["a_L001_R1.fq", "a_L002_R1.fq"], &["r2a", "r2b"], &[], &[]);
+        let inputs = args.inputs().unwrap();
+        assert_eq!(inputs.lanes, vec![Some(1), Some(2)]);
+        assert_eq!(inputs.r2.len(), 2);
+        assert_eq!(inputs.threads, 2);
+        assert!(inputs.index_files().is_none());
+
+        let rejected = |args: InputArgs| {
+            assert!(matches!(
+                args.inputs(),
+                Err(DeezmuxError::InputDiscovery(_))
+            ))
+        };
+
+        // A prefix, or files, not both
+        let mut args = input_args(&["r1"], &[], &[], &[]);
+        args.read_prefix = Some("runs/lib_".to_string());
+        rejected(args);
+        rejected(input_args(&[], &[], &[], &[]));
+
+        // One file per read for every R1 file
+        rejected(input_args(&["r1a", "r1b"], &["r2a"], &[], &[]));
+        rejected(input_args(&["r1a", "r1b"], &[], &["i1a"], &[]));
+        rejected(input_args(&["r1"], &[], &["i1"], &["i2a", "i2b"]));
+        rejected(input_args(&[], &["r2"], &[], &[]));
+
+        rejected(input_args(&["r1"], &[], &[], &["i2"]));
+        rejected(input_args(&["-"], &[], &["-"], &[]));
+
+        let mut args = input_args(&["r1"], &["r2"], &[], &[]);
+        args.interleaved = true;
+        rejected(args);
+        let mut args = input_args(&["r1"], &[], &[], &[]);
+        args.interleaved = true;
+        assert!(args.inputs().unwrap().interleaved);
+    }
 }
@@ -0,0 +1,412 @@
+use clap::ArgEnum;
+use hashbrown::HashMap;
+use triple_accel::*;
+use twox_hash::xxh3::RandomHashBuilder64;
+
+use crate::samplesheet::*;
+
+pub const AMBIGUOUS: &str = "AMBIGUOUS";
+pub const UNASSIGNED: &str = "UNASSIGNED";
+/// i7 and i5 each match a different sample, most likely index hopping
+pub const HOPPED: &str = "HOPPED";
+
+const BASES: &[u8] = b"ACGTN";
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum MatchMode {
+    /// Summed edit distance against every sample, computed once per observed barcode
+    Levenshtein,
+    /// Mismatches only, looked up in a table built from the sample sheet at startup
+    Hamming,
+}
+
+/// What a sequence in the neighbor table resolves to
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Neighbor {
+    /// Index of the closest barcode and the number of mismatches to it
+    Barcode(usize, u32),
+    /// Equally close to two or more different barcodes
+    Ambiguous(u32),
+}
+
+impl Neighbor {
+    fn distance(&self) -> u32 {
+        match self {
+            Neighbor::Barcode(_, d) => *d,
+            Neighbor::Ambiguous(d) => *d,
+        }
+    }
+}
+
+/// Every sequence within N mismatches of every barcode for one index read
+#[derive(Default)]
+struct NeighborTable {
+    neighbors: HashMap<Vec<u8>, Neighbor, RandomHashBuilder64>,
+}
+
+impl NeighborTable {
+    fn insert(&mut self, seq: Vec<u8>, barcode: usize, distance: u32) {
+        let entry = self
+            .neighbors
+            .entry(seq)
+            .or_insert(Neighbor::Barcode(barcode, distance));
+
+        // Closest barcode wins, a tie between two different barcodes is a collision
+        if distance < entry.distance() {
+            *entry = Neighbor::Barcode(barcode, distance);
+        } else if distance == entry.distance() && *entry != Neighbor::Barcode(barcode, distance) {
+            *entry = Neighbor::Ambiguous(distance);
+        }
+    }
+
+    fn get(&self, seq: &str) -> Option<&Neighbor> {
+        self.neighbors.get(seq.as_bytes())
+    }
+}
+
+/// All sequences within `mismatches` substitutions of `seq`, with their distance to it
+pub fn hamming_neighbors(seq: &[u8], mismatches: u32) -> Vec<(Vec<u8>, u32)> {
+    let mut neighbors = vec![(seq.to_vec(), 0)];
+
+    // Each round substitutes one more position, always after the last one changed,
+    // so every neighbor is generated exactly once
+    let mut frontier = vec![(seq.to_vec(), 0)];
+    for distance in 1..=mismatches {
+        let mut next = Vec::new();
+        for (s, start) in frontier.iter() {
+            for pos in *start..s.len() {
+                for base in BASES.iter().filter(|&&b| b != seq[pos]) {
+                    let mut variant = s.clone();
+                    variant[pos] = *base;
+                    next.push((variant, pos + 1));
+                }
+            }
+        }
+        neighbors.extend(next.iter().map(|(s, _)| (s.clone(), distance)));
+        frontier = next;
+    }
+
+    neighbors
+}
+
+/// Distance between two barcodes for a single index under the given matching mode
+pub fn index_distance(mode: MatchMode, a: &[u8], b: &[u8]) -> u32 {
+    match mode {
+        MatchMode::Hamming if a.len() == b.len() => hamming(a, b),
+        _ => levenshtein(a, b),
+    }
+}
+
+/// Hamming lookup tables for both index reads
+struct HammingTable {
+    i7: NeighborTable,
+    i5: NeighborTable,
+}
+
+impl HammingTable {
+    fn new(i7_barcodes: &[String], i5_barcodes: &[String], mismatches: (u32, u32)) -> HammingTable {
+        let mut i7 = NeighborTable::default();
+        let mut i5 = NeighborTable::default();
+
+        for (idx, bc) in i7_barcodes.iter().enumerate() {
+            for (seq, distance) in hamming_neighbors(bc.as_bytes(), mismatches.0) {
+                i7.insert(seq, idx, distance);
+            }
+        }
+
+        for (idx, bc) in i5_barcodes.iter().enumerate() {
+            for (seq, distance) in hamming_neighbors(bc.as_bytes(), mismatches.1) {
+                i5.insert(seq, idx, distance);
+            }
+        }
+
+        HammingTable { i7, i5 }
+    }
+}
+
+/// The barcode closest to `seq` within `mismatches`, None if there is none or it is a tie
+fn closest_barcode(
+    mode: MatchMode,
+    seq: &str,
+    barcodes: &[String],
+    mismatches: u32,
+) -> Option<usize> {
+    let mut distances: Vec<(usize, u32)> = barcodes
+        .iter()
+        .map(|x| index_distance(mode, seq.as_bytes(), x.as_bytes()))
+        .enumerate()
+        .filter(|x| x.1 <= mismatches)
+        .collect();
+    distances.sort_by_key(|x| x.1);
+
+    match distances.as_slice() {
+        [(_, min), (_, second), ..] if min == second => None,
+        [(idx, _), ..] => Some(*idx),
+        [] => None,
+    }
+}
+
+/// Assigns observed barcodes (`i7+i5`, or `i7` alone) to a sample ID, AMBIGUOUS, HOPPED or
+/// UNASSIGNED
+pub struct BarcodeMatcher {
+    samples: SampleSheet,
+    mode: MatchMode,
+    // False for single-index sheets, where only i7 is scored
+    dual: bool,
+    // Maximum distance allowed for i7 and i5
+    mismatches: (u32, u32),
+    // Samples may share one of their indexes, so indexes are matched by distinct barcode
+    i7_barcodes: Vec<String>,
+    i5_barcodes: Vec<String>,
+    // Sample of each (i7, i5) barcode pair, None when the pair appears for more than one sample
+    pairs: HashMap<(usize, usize), Option<usize>, RandomHashBuilder64>,
+    hamming: Option<HammingTable>,
+}
+
+impl BarcodeMatcher {
+    pub fn new(mode: MatchMode, samples: SampleSheet, mismatches: (u32, u32)) -> BarcodeMatcher {
+        let mut i7_barcodes: Vec<String> = Vec::new();
+        let mut i5_barcodes: Vec<String> = Vec::new();
+        let mut pairs: HashMap<(usize, usize), Option<usize>, RandomHashBuilder64> =
+            Default::default();
+
+        let position =
+            |barcodes: &mut Vec<String>, bc: &str| match barcodes.iter().position(|x| x == bc) {
+                Some(idx) => idx,
+                None => {
+                    barcodes.push(bc.to_string());
+                    barcodes.len() - 1
+                }
+            };
+
+        for (idx, sample) in samples.iter().enumerate() {
+            let a = position(&mut i7_barcodes, &sample.i7);
+            let b = position(&mut i5_barcodes, sample.i5.as_deref().unwrap_or(""));
+
+            pairs
+                .entry((a, b))
+                .and_modify(|x| *x = None)
+                .or_insert(Some(idx));
+        }
+
+        let hamming = match mode {
+            MatchMode::Levenshtein => None,
+            MatchMode::Hamming => Some(HammingTable::new(&i7_barcodes, &i5_barcodes, mismatches)),
+        };
+
+        BarcodeMatcher {
+            dual: samples.iter().any(|x| x.i5.is_some()),
+            samples,
+            mode,
+            mismatches,
+            i7_barcodes,
+            i5_barcodes,
+            pairs,
+            hamming,
+        }
+    }
+
+    pub fn samples(&self) -> &SampleSheet {
+        &self.samples
+    }
+
+    /// The distinct i7 and i5 barcodes of the sample sheet, in the order `resolve_indexes` uses
+    pub fn index_barcodes(&self) -> (&[String], &[String]) {
+        (&self.i7_barcodes, &self.i5_barcodes)
+    }
+
+    /// Matches each index of an observed barcode on its own, giving the position of its
+    /// closest sample sheet barcode within the allowed mismatches
+    pub fn resolve_indexes(&self, barcode: &str) -> (Option<usize>, Option<usize>) {
+        let mut read_barcodes = barcode.split('+');
+        let read_i7 = read_barcodes.next().unwrap_or("");
+        let read_i5 = match self.dual {
+            true => read_barcodes.next().unwrap_or(""),
+            false => "",
+        };
+
+        let resolve = |x: Option<&Neighbor>| match x {
+            Some(Neighbor::Barcode(idx, _)) => Some(*idx),
+            _ => None,
+        };
+
+        match &self.hamming {
+            Some(table) => (
+                resolve(table.i7.get(read_i7)),
+                resolve(table.i5.get(read_i5)),
+            ),
+            None => (
+                closest_barcode(self.mode, read_i7, &self.i7_barcodes, self.mismatches.0),
+                closest_barcode(self.mode, read_i5, &self.i5_barcodes, self.mismatches.1),
+            ),
+        }
+    }
+
+    pub fn assign(&self, barcode: &str) -> &str {
+        // Reads from a dual-index run can still be split with a single-index sheet
+        let barcode = match self.dual {
+            true => barcode,
+            false => barcode.split('+').next().unwrap_or(""),
+        };
+
+        // Both indexes known but from different samples is hopping, not noise. Checked first
+        // because the summed score could still put the read in range of one of the samples.
+        if self.dual {
+            if let (Some(a), Some(b)) = self.resolve_indexes(barcode) {
+                if !self.pairs.contains_key(&(a, b)) {
+                    return HOPPED;
+                }
+            }
+        }
+
+        match &self.hamming {
+            Some(table) => self.assign_hamming(table, barcode),
+            None => self.assign_levenshtein(barcode),
+        }
+    }
+
+    fn assign_hamming(&self, table: &HammingTable, barcode: &str) -> &str {
+        let mut read_barcodes = barcode.split('+');
+        let i7 = table.i7.get(read_barcodes.next().unwrap_or(""));
+        let i5 = table.i5.get(read_barcodes.next().unwrap_or(""));
+
+        match (i7, i5) {
+            (Some(Neighbor::Barcode(a, _)), Some(Neighbor::Barcode(b, _))) => {
+                match self.pairs.get(&(*a, *b)) {
+                    Some(Some(sample)) => &self.samples.get(*sample).id,
+                    Some(None) => AMBIGUOUS,
+                    None => UNASSIGNED,
+                }
+            }
+            (Some(_), Some(_)) => AMBIGUOUS,
+            _ => UNASSIGNED,
+        }
+    }
+
+    fn assign_levenshtein(&self, barcode: &str) -> &str {
+        let mut read_barcodes = barcode.split('+');
+        let read_i7 = read_barcodes.next().unwrap_or("");
+        let read_i5 = read_barcodes.next().unwrap_or("");
+
+        // Each index has to be within its own threshold, the summed distance only ranks samples
+        let mut scores: Vec<(&String, u32)> = self
+            .samples
+            .iter()
+            .filter_map(|sample| {
+                let i5 = sample.i5.as_deref().unwrap_or("");
+                let dist1 = levenshtein(read_i7.as_bytes(), sample.i7.as_bytes());
+                let dist2 = levenshtein(read_i5.as_bytes(), i5.as_bytes());
+
+                if dist1 <= self.mismatches.0 && dist2 <= self.mismatches.1 {
+                    Some((&sample.id, dist1 + dist2))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        scores.sort_by_key(|x| x.1);
+
+        match scores.as_slice() {
+            [] => UNASSIGNED,
+            [(_, min), (_, second), ..] if min == second => AMBIGUOUS,
+            [(min_id, _), ..] => min_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn barcodes() -> SampleSheet {
+        vec![
+            Sample::new("S1", "AAAAAAAA", Some("CCCCCCCC")),
+            Sample::new("S2", "AAAAAAAT", Some("GGGGGGGG")),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    #[test]
+    fn neighbor_counts() {
+        // 8 positions, 4 alternative bases each
+        assert_eq!(hamming_neighbors(b"ACGTACGT", 1).len(), 1 + 8 * 4);
+        assert_eq!(hamming_neighbors(b"ACGTACGT", 2).len(), 1 + 8 * 4 + 28 * 16);
+    }
+
+    #[test]
+    fn hamming_assignment() {
+        let matcher = BarcodeMatcher::new(MatchMode::Hamming, barcodes(), (1, 1));
+
+        assert_eq!(matcher.assign("AAAAAAAA+CCCCCCCC"), "S1");
+        assert_eq!(matcher.assign("NAAAAAAA+CCCCCCCA"), "S1");
+        assert_eq!(matcher.assign("AAAAAAAT+GGGGGGGG"), "S2");
+        // Exact match beats the 1 mismatch neighbor of the other sample, i5 is from S1
+        assert_eq!(matcher.assign("AAAAAAAT+CCCCCCCC"), HOPPED);
+        // One mismatch from both i7 barcodes
+        assert_eq!(matcher.assign("AAAAAAAG+CCCCCCCC"), AMBIGUOUS);
+        assert_eq!(matcher.assign("AAAAAAAA+CCCCCCGG"), UNASSIGNED);
+    }
+
+    #[test]
+    fn levenshtein_assignment() {
+        let matcher = BarcodeMatcher::new(MatchMode::Levenshtein, barcodes(), (2, 2));
+
+        assert_eq!(matcher.assign("AAAAAAAA+CCCCCCCC"), "S1");
+        assert_eq!(matcher.assign("TTTTTTTT+TTTTTTTT"), UNASSIGNED);
+    }
+
+    #[test]
+    fn single_index() {
+        let samples: SampleSheet = vec![
+            Sample::new("S1", "AAAAAAAA", None),
+            Sample::new("S2", "GGGGGGGG", None),
+        ]
+        .into_iter()
+        .collect();
+
+        for mode in [MatchMode::Levenshtein, MatchMode::Hamming] {
+            let matcher = BarcodeMatcher::new(mode, samples.clone(), (1, 1));
+            assert_eq!(matcher.assign("AAAAAAAA"), "S1");
+            assert_eq!(matcher.assign("AAAAAAAT"), "S1");
+            // i5 from a dual-index run is ignored
+            assert_eq!(matcher.assign("GGGGGGGG+CCCCCCCC"), "S2");
+            assert_eq!(matcher.assign("CCCCCCCC"), UNASSIGNED);
+        }
+    }
+
+    #[test]
+    fn index_hopping() {
+        for mode in [MatchMode::Levenshtein, MatchMode::Hamming] {
+            let matcher = BarcodeMatcher::new(mode, barcodes(), (1, 1));
+            assert_eq!(matcher.assign("AAAAAAAA+GGGGGGGG"), HOPPED);
+            assert_eq!(
+                matcher.resolve_indexes("AAAAAAAA+GGGGGGGG"),
+                (Some(0), Some(1))
+            );
+            assert_eq!(matcher.assign("TTTTTTTT+GGGGGGGG"), UNASSIGNED);
+        }
+
+        // Single-index sheets can't tell
+        let samples: SampleSheet = vec![Sample::new("S1", "AAAAAAAA", None)]
+            .into_iter()
+            .collect();
+        let matcher = BarcodeMatcher::new(MatchMode::Hamming, samples, (1, 1));
+        assert_eq!(matcher.assign("CCCCCCCC+GGGGGGGG"), UNASSIGNED);
+    }
+
+    #[test]
+    fn per_index_thresholds() {
+        // 3 mismatches in i5 only, a summed score of 3 would have passed before
+        let matcher = BarcodeMatcher::new(MatchMode::Levenshtein, barcodes(), (2, 2));
+        assert_eq!(matcher.assign("AAAAAAAA+CCCCCTTT"), UNASSIGNED);
+
+        let matcher = BarcodeMatcher::new(MatchMode::Levenshtein, barcodes(), (0, 3));
+        assert_eq!(matcher.assign("AAAAAAAA+CCCCCTTT"), "S1");
+        assert_eq!(matcher.assign("AAAAAAAC+CCCCCCCC"), UNASSIGNED);
+
+        let matcher = BarcodeMatcher::new(MatchMode::Hamming, barcodes(), (0, 2));
+        assert_eq!(matcher.assign("AAAAAAAA+CCCCCCTT"), "S1");
+        assert_eq!(matcher.assign("AAAAAAAC+CCCCCCCC"), UNASSIGNED);
+    }
+}
@@ -0,0 +1,149 @@
+use clap::ArgEnum;
+
+use crate::matcher::*;
+use crate::samplesheet::*;
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum I5Orientation {
+    /// i5 in the reads is the same as in the sample sheet
+    Forward,
+    /// i5 in the reads is the reverse complement of the sample sheet (reverse-strand workflow)
+    Revcomp,
+    /// Try every layout on the first reads and use whichever assigns the most
+    Auto,
+}
+
+/// How the indexes in the sample sheet relate to the indexes in the reads
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IndexLayout {
+    Forward,
+    RevcompI5,
+    Swapped,
+    SwappedRevcompI5,
+}
+
+impl IndexLayout {
+    pub fn all() -> [IndexLayout; 4] {
+        [
+            IndexLayout::Forward,
+            IndexLayout::RevcompI5,
+            IndexLayout::Swapped,
+            IndexLayout::SwappedRevcompI5,
+        ]
+    }
+
+    /// Rewrites the sample sheet so its barcodes are as they appear in the reads
+    pub fn apply(&self, samples: &SampleSheet) -> SampleSheet {
+        samples
+            .iter()
+            .map(|sample| {
+                let mut sample = sample.clone();
+
+                if matches!(self, IndexLayout::RevcompI5 | IndexLayout::SwappedRevcompI5) {
+                    sample.i5 = sample.i5.as_deref().map(reverse_complement);
+                }
+
+                if matches!(self, IndexLayout::Swapped | IndexLayout::SwappedRevcompI5) {
+                    if let Some(i5) = sample.i5.take() {
+                        sample.i5 = Some(std::mem::replace(&mut sample.i7, i5));
+                    }
+                }
+
+                sample
+            })
+            .collect()
+    }
+}
+
+pub fn reverse_complement(seq: &str) -> String {
+    seq.bytes()
+        .rev()
+        .map(|b| match b {
+            b'A' => 'T',
+            b'C' => 'G',
+            b'G' => 'C',
+            b'T' => 'A',
+            _ => 'N',
+        })
+        .collect()
+}
+
+/// Fraction of `barcodes` assigned to a sample when the sheet is read with `layout`
+fn match_rate(
+    samples: &SampleSheet,
+    layout: IndexLayout,
+    mode: MatchMode,
+    mismatches: (u32, u32),
+    barcodes: &[String],
+) -> f64 {
+    let matcher = BarcodeMatcher::new(mode, layout.apply(samples), mismatches);
+
+    let assigned = barcodes
+        .iter()
+        .filter(|x| !matches!(matcher.assign(x), AMBIGUOUS | UNASSIGNED | HOPPED))
+        .count();
+
+    assigned as f64 / barcodes.len().max(1) as f64
+}
+
+/// Picks the layout that assigns the most of `barcodes`, sampled from the start of the run.
+/// Single-index sheets are always forward.
+pub fn detect_layout(
+    samples: &SampleSheet,
+    mode: MatchMode,
+    mismatches: (u32, u32),
+    barcodes: &[String],
+) -> IndexLayout {
+    if samples.iter().all(|x| x.i5.is_none()) {
+        return IndexLayout::Forward;
+    }
+
+    let mut best = (IndexLayout::Forward, -1.0);
+
+    for layout in IndexLayout::all() {
+        let rate = match_rate(samples, layout, mode, mismatches, barcodes);
+        println!("Index layout {:?}: {:.2}% assigned", layout, rate * 100.0);
+
+        if rate > best.1 {
+            best = (layout, rate);
+        }
+    }
+
+    best.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples() -> SampleSheet {
+        vec![
+            Sample::new("S1", "AAAACCCC", Some("GGGATTTT")),
+            Sample::new("S2", "CACACACA", Some("GTGTAAAC")),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    #[test]
+    fn revcomp() {
+        assert_eq!(reverse_complement("AACGTN"), "NACGTT");
+    }
+
+    #[test]
+    fn detects_revcomp_i5() {
+        let barcodes: Vec<String> = vec![
+            "AAAACCCC+AAAATCCC".to_string(),
+            "CACACACA+GTTTACAC".to_string(),
+        ];
+        let layout = detect_layout(&samples(), MatchMode::Hamming, (1, 1), &barcodes);
+        assert_eq!(layout, IndexLayout::RevcompI5);
+    }
+
+    #[test]
+    fn detects_swapped() {
+        let barcodes: Vec<String> = vec!["GGGATTTT+AAAACCCC".to_string()];
+        let layout = detect_layout(&samples(), MatchMode::Hamming, (1, 1), &barcodes);
+        assert_eq!(layout, IndexLayout::Swapped);
+    }
+}
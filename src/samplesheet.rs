@@ -0,0 +1,395 @@
+use crate::error::DeezmuxError;
+
+/// A sample from the barcode file or sample sheet
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub id: String,
+    pub i7: String,
+    pub i5: Option<String>,
+    /// Lanes the sample is expected in, empty for every lane
+    pub lanes: Vec<u32>,
+    pub project: Option<String>,
+    /// Base name of the output files
+    pub output_name: String,
+    /// Any other columns, as (column name, value)
+    pub metadata: Vec<(String, String)>,
+}
+
+impl Sample {
+    pub fn new(id: &str, i7: &str, i5: Option<&str>) -> Sample {
+        Sample {
+            id: id.to_string(),
+            i7: i7.to_string(),
+            i5: i5.map(|x| x.to_string()),
+            lanes: Vec::new(),
+            project: None,
+            output_name: id.to_string(),
+            metadata: Vec::new(),
+        }
+    }
+}
+
+/// All samples in a run
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SampleSheet {
+    samples: Vec<Sample>,
+}
+
+impl SampleSheet {
+    pub fn new() -> SampleSheet {
+        SampleSheet::default()
+    }
+
+    /// Adds a sample. A sample repeated with the same barcodes (one row per lane) is merged
+    /// into the existing entry.
+    pub fn push(&mut self, sample: Sample) {
+        match self
+            .samples
+            .iter_mut()
+            .find(|x| x.id == sample.id && x.i7 == sample.i7 && x.i5 == sample.i5)
+        {
+            Some(x) => x.lanes.extend(sample.lanes),
+            None => self.samples.push(sample),
+        }
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Sample> {
+        self.samples.iter()
+    }
+
+    pub fn get(&self, i: usize) -> &Sample {
+        &self.samples[i]
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// The samples expected in `lane`, every sample when the lane is not known
+    pub fn for_lane(&self, lane: Option<u32>) -> SampleSheet {
+        self.iter()
+            .filter(|x| match lane {
+                Some(lane) => x.lanes.is_empty() || x.lanes.contains(&lane),
+                None => true,
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+impl FromIterator<Sample> for SampleSheet {
+    fn from_iter<I: IntoIterator<Item = Sample>>(iter: I) -> SampleSheet {
+        let mut sheet = SampleSheet::new();
+        for sample in iter {
+            sheet.push(sample);
+        }
+        sheet
+    }
+}
+
+/// The original barcode file format: a header line, then `id,bc0+bc1,...` with any further
+/// columns kept as metadata
+pub fn parse_barcode_csv(contents: &str) -> Result<SampleSheet, DeezmuxError> {
+    let mut lines = contents.lines();
+    let header: Vec<&str> = lines.next().map_or(Vec::new(), cells);
+
+    let mut sheet = SampleSheet::new();
+
+    for (i, line) in lines.enumerate().filter(|(_, x)| !x.trim().is_empty()) {
+        let j = cells(line);
+        let z: Vec<String> = match j.get(1) {
+            Some(barcodes) if !barcodes.is_empty() => {
+                barcodes.split('+').map(|x| x.to_uppercase()).collect()
+            }
+            _ => {
+                return Err(DeezmuxError::SampleSheet(format!(
+                    "Line {} has no barcode: {}",
+                    i + 2,
+                    line
+                )))
+            }
+        };
+
+        let mut sample = Sample::new(j[0], &z[0], z.get(1).map(|x| x.as_str()));
+        sample.metadata = header
+            .iter()
+            .zip(j.iter())
+            .skip(2)
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        sheet.push(sample);
+    }
+
+    Ok(sheet)
+}
+
+/// The parts of an Illumina SampleSheet.csv deezmux cares about
+#[derive(Debug, Default)]
+pub struct IlluminaSampleSheet {
+    pub version: u32,
+    pub index1_cycles: Option<usize>,
+    pub index2_cycles: Option<usize>,
+    pub samples: SampleSheet,
+}
+
+/// True if the file looks like an Illumina sample sheet, ie: it starts with a [Section]
+pub fn is_illumina_sample_sheet(contents: &str) -> bool {
+    contents
+        .lines()
+        .map(|x| x.trim())
+        .find(|x| !x.is_empty())
+        .is_some_and(|x| x.starts_with('['))
+}
+
+fn cells(line: &str) -> Vec<&str> {
+    line.split(',')
+        .map(|x| x.trim().trim_matches('"'))
+        .collect()
+}
+
+/// Splits the sheet into (section name, lines) pairs, dropping blank and comma-only lines
+fn sections(contents: &str) -> Vec<(&str, Vec<&str>)> {
+    let mut sections: Vec<(&str, Vec<&str>)> = Vec::new();
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.trim_end_matches(',').is_empty() {
+            continue;
+        }
+
+        let first = cells(line)[0];
+        if first.starts_with('[') && first.ends_with(']') {
+            sections.push((&first[1..first.len() - 1], Vec::new()));
+        } else if let Some((_, lines)) = sections.last_mut() {
+            lines.push(line);
+        }
+    }
+
+    sections
+}
+
+/// Parses v1 (bcl2fastq) and v2 (BCL Convert) sample sheets. Columns are found by name,
+/// so their order and any extra columns do not matter. Samples listed once per lane are
+/// merged into one entry.
+pub fn parse_illumina_sample_sheet(contents: &str) -> Result<IlluminaSampleSheet, DeezmuxError> {
+    let error = |message: &str| DeezmuxError::SampleSheet(message.to_string());
+
+    let mut sheet = IlluminaSampleSheet {
+        version: 1,
+        ..Default::default()
+    };
+
+    let sections = sections(contents);
+
+    for (name, lines) in sections.iter() {
+        match *name {
+            "Header" => {
+                for line in lines.iter() {
+                    let c = cells(line);
+                    if c[0] == "FileFormatVersion" && c.len() > 1 {
+                        sheet.version = c[1]
+                            .parse()
+                            .map_err(|_| error("Invalid FileFormatVersion"))?;
+                    }
+                }
+            }
+            "Reads" => {
+                for line in lines.iter() {
+                    let c = cells(line);
+                    let cycles = || c.get(1).and_then(|x| x.parse().ok());
+                    match c[0] {
+                        "Index1Cycles" => sheet.index1_cycles = cycles(),
+                        "Index2Cycles" => sheet.index2_cycles = cycles(),
+                        _ => (),
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // v2 sheets keep samples in [BCLConvert_Data], but fall back to whichever is present
+    let (preferred, fallback) = match sheet.version {
+        1 => ("Data", "BCLConvert_Data"),
+        _ => ("BCLConvert_Data", "Data"),
+    };
+
+    let data = sections
+        .iter()
+        .find(|(name, _)| *name == preferred)
+        .or_else(|| sections.iter().find(|(name, _)| *name == fallback))
+        .ok_or_else(|| error("No [Data] or [BCLConvert_Data] section"))?;
+
+    let (columns, rows) = data
+        .1
+        .split_first()
+        .ok_or_else(|| error("No header in the data section"))?;
+    let header = cells(columns);
+    let columns: Vec<String> = header.iter().map(|x| x.to_lowercase()).collect();
+    let column = |name: &str| columns.iter().position(|x| x == name);
+
+    let sample_id = column("sample_id").ok_or_else(|| error("No Sample_ID column"))?;
+    let index = column("index").ok_or_else(|| error("No index column"))?;
+    let index2 = column("index2");
+    let lane = column("lane");
+    let project = column("sample_project");
+
+    let known = [Some(sample_id), Some(index), index2, lane, project];
+
+    for row in rows.iter() {
+        let c = cells(row);
+        let get = |i: Option<usize>| i.and_then(|i| c.get(i)).filter(|x| !x.is_empty());
+
+        let id = get(Some(sample_id))
+            .ok_or_else(|| error(&format!("Row without a Sample_ID: {}", row)))?;
+        let mut i7 = get(Some(index))
+            .ok_or_else(|| error(&format!("Row without an index: {}", row)))?
+            .to_uppercase();
+        let mut i5 = get(index2).map(|x| x.to_uppercase());
+
+        // BCL Convert only reads as many index cycles as the run has
+        if let Some(n) = sheet.index1_cycles {
+            i7.truncate(n);
+        }
+        if let (Some(n), Some(i5)) = (sheet.index2_cycles, i5.as_mut()) {
+            i5.truncate(n);
+        }
+
+        let mut sample = Sample::new(id, &i7, i5.as_deref());
+        sample.lanes = match get(lane) {
+            Some(x) => vec![x
+                .parse()
+                .map_err(|_| error(&format!("Invalid Lane: {}", x)))?],
+            None => Vec::new(),
+        };
+        sample.project = get(project).map(|x| x.to_string());
+        sample.metadata = header
+            .iter()
+            .zip(c.iter())
+            .enumerate()
+            .filter(|(i, _)| !known.contains(&Some(*i)))
+            .map(|(_, (k, v))| (k.to_string(), v.to_string()))
+            .collect();
+
+        sheet.samples.push(sample);
+    }
+
+    Ok(sheet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V1: &str = "[Header],,,,
+IEMFileVersion,4,,,
+Date,1/1/2022,,,
+,,,,
+[Reads],,,,
+151,,,,
+151,,,,
+,,,,
+[Data],,,,
+Lane,Sample_ID,Sample_Name,index,index2,Sample_Project
+1,S1,S1,aaaaaaaa,CCCCCCCC,ProjA
+2,S1,S1,aaaaaaaa,CCCCCCCC,ProjA
+1,S2,S2,GGGGGGGG,TTTTTTTT,ProjB
+";
+
+    const V2: &str = "[Header]
+FileFormatVersion,2
+RunName,Test
+
+[Reads]
+Read1Cycles,151
+Read2Cycles,151
+Index1Cycles,8
+Index2Cycles,8
+
+[BCLConvert_Settings]
+AdapterRead1,CTGTCTCTTATACACATCT
+
+[BCLConvert_Data]
+Sample_ID,Index,Index2
+S1,AAAAAAAAGT,CCCCCCCCAC
+S2,GGGGGGGG,TTTTTTTT
+";
+
+    #[test]
+    fn detects_format() {
+        assert!(is_illumina_sample_sheet(V1));
+        assert!(is_illumina_sample_sheet(V2));
+        assert!(!is_illumina_sample_sheet("id,bc0+bc1,seq0,seq1\n"));
+    }
+
+    #[test]
+    fn parses_barcode_csv() {
+        let sheet = parse_barcode_csv("id,bc,seq0,seq1\nS1,AAAAAAAA+CCCCCCCC,x,y\n").unwrap();
+        let mut sample = Sample::new("S1", "AAAAAAAA", Some("CCCCCCCC"));
+        sample.metadata = vec![
+            ("seq0".to_string(), "x".to_string()),
+            ("seq1".to_string(), "y".to_string()),
+        ];
+        assert_eq!(sheet.get(0), &sample);
+
+        assert!(parse_barcode_csv("id,bc\nS1\n").is_err());
+
+        // Upper case, like Illumina sample sheets
+        let sheet = parse_barcode_csv("id,bc\nS1,acgtacgt+ttgg\n").unwrap();
+        assert_eq!(sheet.get(0).i7, "ACGTACGT");
+        assert_eq!(sheet.get(0).i5.as_deref(), Some("TTGG"));
+    }
+
+    #[test]
+    fn parses_v1() {
+        let sheet = parse_illumina_sample_sheet(V1).unwrap();
+        assert_eq!(sheet.version, 1);
+        assert_eq!(
+            sheet.samples.get(0),
+            &Sample {
+                id: "S1".to_string(),
+                i7: "AAAAAAAA".to_string(),
+                i5: Some("CCCCCCCC".to_string()),
+                lanes: vec![1, 2],
+                project: Some("ProjA".to_string()),
+                output_name: "S1".to_string(),
+                metadata: vec![("Sample_Name".to_string(), "S1".to_string())],
+            }
+        );
+        assert_eq!(sheet.samples.len(), 2);
+
+        let no_index = V1.replace("1,S2,S2,GGGGGGGG,", "1,S2,S2,,");
+        match parse_illumina_sample_sheet(&no_index) {
+            Err(DeezmuxError::SampleSheet(message)) => {
+                assert!(message.starts_with("Row without an index: 1,S2"))
+            }
+            _ => panic!("Expected a sample sheet error"),
+        }
+    }
+
+    #[test]
+    fn parses_v2() {
+        let sheet = parse_illumina_sample_sheet(V2).unwrap();
+        assert_eq!(sheet.version, 2);
+        assert_eq!(sheet.samples.len(), 2);
+        // Trimmed to the index cycles in [Reads]
+        assert_eq!(sheet.samples.get(0).i7, "AAAAAAAA");
+        assert_eq!(sheet.samples.get(0).i5.as_deref(), Some("CCCCCCCC"));
+        assert!(sheet.samples.get(0).lanes.is_empty());
+    }
+
+    #[test]
+    fn samples_by_lane() {
+        let sheet = parse_illumina_sample_sheet(V1).unwrap().samples;
+        let ids = |x: SampleSheet| x.iter().map(|x| x.id.clone()).collect::<Vec<String>>();
+
+        assert_eq!(ids(sheet.for_lane(Some(1))), vec!["S1", "S2"]);
+        assert_eq!(ids(sheet.for_lane(Some(2))), vec!["S1"]);
+        assert_eq!(ids(sheet.for_lane(None)), vec!["S1", "S2"]);
+    }
+}
@@ -0,0 +1,653 @@
+use hashbrown::HashMap;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+use crate::error::DeezmuxError;
+use crate::matcher::*;
+use crate::samplesheet::*;
+
+/// Read pairs assigned to one sample
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SampleStats {
+    pub id: String,
+    /// Barcodes as they appear in the reads, `i7-i5` like BCL Convert
+    pub index: String,
+    pub reads: usize,
+    /// Reads whose barcode matched the sample exactly
+    pub perfect_index_reads: usize,
+    pub mismatched_index_reads: usize,
+    pub percent_of_total: f64,
+}
+
+/// Summary of a demultiplexing run, counted in read pairs
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct DemuxStats {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lane: Option<u32>,
+    pub total_reads: usize,
+    pub assigned_reads: usize,
+    pub ambiguous_reads: usize,
+    pub unassigned_reads: usize,
+    pub hopped_reads: usize,
+    /// Hopped reads as a fraction of assigned plus hopped reads, the reads where both indexes
+    /// matched a sample. Not of `total_reads`, like the percentages elsewhere.
+    pub hopping_rate: f64,
+    pub samples: Vec<SampleStats>,
+    /// None for single-index sheets
+    pub index_hopping: Option<IndexHopping>,
+    /// AMBIGUOUS and UNASSIGNED barcodes with their read counts, most common first
+    #[serde(skip)]
+    pub undetermined: Vec<(String, usize)>,
+    /// Stats for each lane when a multi-lane run was merged
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub lanes: Vec<DemuxStats>,
+}
+
+/// Reads counted by the sample sheet i7 and i5 barcode they matched on their own.
+/// Counts off the sample pairs are index hopping.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct IndexHopping {
+    pub i7: Vec<String>,
+    pub i5: Vec<String>,
+    /// One row per i7, one column per i5
+    pub counts: Vec<Vec<usize>>,
+}
+
+impl IndexHopping {
+    /// Adds the counts of `other`, which may have been built from a different set of barcodes
+    fn add(&mut self, other: &IndexHopping) {
+        for (a, i7) in other.i7.iter().enumerate() {
+            let row = match self.i7.iter().position(|x| x == i7) {
+                Some(row) => row,
+                None => {
+                    self.i7.push(i7.clone());
+                    self.counts.push(vec![0; self.i5.len()]);
+                    self.i7.len() - 1
+                }
+            };
+
+            for (b, i5) in other.i5.iter().enumerate() {
+                let column = match self.i5.iter().position(|x| x == i5) {
+                    Some(column) => column,
+                    None => {
+                        self.i5.push(i5.clone());
+                        for counts in self.counts.iter_mut() {
+                            counts.push(0);
+                        }
+                        self.i5.len() - 1
+                    }
+                };
+
+                self.counts[row][column] += other.counts[a][b];
+            }
+        }
+    }
+}
+
+/// A barcode that was not assigned, and the sample it came closest to
+#[derive(Debug, PartialEq)]
+pub struct UndeterminedBarcode {
+    pub barcode: String,
+    pub reads: usize,
+    /// Closest samples by summed distance, more than one when tied
+    pub nearest: Vec<String>,
+    pub distance: u32,
+    /// Whether the i7 (and i5) alone is within the allowed mismatches of any sample
+    pub i7_match: bool,
+    pub i5_match: Option<bool>,
+}
+
+fn percent(n: usize, total: usize) -> f64 {
+    n as f64 * 100.0 / total.max(1) as f64
+}
+
+impl DemuxStats {
+    /// Builds the stats from every observed barcode, the sample it was assigned to and how
+    /// many reads had it. Samples are listed in sample sheet order, including empty ones.
+    pub fn new<'a, I>(matcher: &BarcodeMatcher, barcodes: I) -> DemuxStats
+    where
+        I: IntoIterator<Item = (&'a str, &'a str, usize)>,
+    {
+        let samples = matcher.samples();
+        let dual = samples.iter().any(|x| x.i5.is_some());
+        let (i7_barcodes, i5_barcodes) = matcher.index_barcodes();
+
+        let mut stats = DemuxStats {
+            index_hopping: match dual {
+                true => Some(IndexHopping {
+                    i7: i7_barcodes.to_vec(),
+                    i5: i5_barcodes.to_vec(),
+                    counts: vec![vec![0; i5_barcodes.len()]; i7_barcodes.len()],
+                }),
+                false => None,
+            },
+            ..Default::default()
+        };
+
+        for sample in samples.iter() {
+            if stats.samples.iter().all(|x| x.id != sample.id) {
+                let index = match &sample.i5 {
+                    Some(i5) => format!("{}-{}", sample.i7, i5),
+                    None => sample.i7.clone(),
+                };
+
+                stats.samples.push(SampleStats {
+                    id: sample.id.clone(),
+                    index,
+                    reads: 0,
+                    perfect_index_reads: 0,
+                    mismatched_index_reads: 0,
+                    percent_of_total: 0.0,
+                });
+            }
+        }
+
+        for (barcode, assigned, count) in barcodes {
+            stats.total_reads += count;
+
+            if let Some(hopping) = stats.index_hopping.as_mut() {
+                if let (Some(a), Some(b)) = matcher.resolve_indexes(barcode) {
+                    hopping.counts[a][b] += count;
+                }
+            }
+
+            if matches!(assigned, AMBIGUOUS | UNASSIGNED) {
+                stats.undetermined.push((barcode.to_string(), count));
+            }
+
+            match assigned {
+                AMBIGUOUS => stats.ambiguous_reads += count,
+                UNASSIGNED => stats.unassigned_reads += count,
+                HOPPED => stats.hopped_reads += count,
+                id => {
+                    stats.assigned_reads += count;
+
+                    // Single-index sheets only look at i7, even if the reads have an i5
+                    let barcode = match dual {
+                        true => barcode,
+                        false => barcode.split('+').next().unwrap_or(""),
+                    };
+
+                    let perfect = samples.iter().filter(|x| x.id == id).any(|x| {
+                        let mut read_barcodes = barcode.split('+');
+                        read_barcodes.next() == Some(x.i7.as_str())
+                            && read_barcodes.next() == x.i5.as_deref()
+                    });
+
+                    if let Some(sample) = stats.samples.iter_mut().find(|x| x.id == id) {
+                        sample.reads += count;
+                        match perfect {
+                            true => sample.perfect_index_reads += count,
+                            false => sample.mismatched_index_reads += count,
+                        }
+                    }
+                }
+            }
+        }
+
+        stats.finish();
+        stats
+    }
+
+    /// Fills in the fractions once all reads are counted
+    fn finish(&mut self) {
+        for sample in self.samples.iter_mut() {
+            sample.percent_of_total = percent(sample.reads, self.total_reads);
+        }
+
+        self.hopping_rate =
+            self.hopped_reads as f64 / (self.assigned_reads + self.hopped_reads).max(1) as f64;
+
+        self.undetermined
+            .sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    }
+
+    /// Adds up the stats of each lane, keeping them as a per-lane breakdown. A single lane
+    /// is returned as is.
+    pub fn merge_lanes(mut lanes: Vec<DemuxStats>) -> DemuxStats {
+        if lanes.len() == 1 {
+            return lanes.remove(0);
+        }
+
+        let mut stats = DemuxStats::default();
+        let mut undetermined: HashMap<&str, usize> = HashMap::new();
+
+        for lane in lanes.iter() {
+            stats.total_reads += lane.total_reads;
+            stats.assigned_reads += lane.assigned_reads;
+            stats.ambiguous_reads += lane.ambiguous_reads;
+            stats.unassigned_reads += lane.unassigned_reads;
+            stats.hopped_reads += lane.hopped_reads;
+
+            for sample in lane.samples.iter() {
+                match stats.samples.iter_mut().find(|x| x.id == sample.id) {
+                    Some(x) => {
+                        x.reads += sample.reads;
+                        x.perfect_index_reads += sample.perfect_index_reads;
+                        x.mismatched_index_reads += sample.mismatched_index_reads;
+                    }
+                    None => stats.samples.push(sample.clone()),
+                }
+            }
+
+            if let Some(hopping) = &lane.index_hopping {
+                stats
+                    .index_hopping
+                    .get_or_insert_with(IndexHopping::default)
+                    .add(hopping);
+            }
+
+            for (barcode, count) in lane.undetermined.iter() {
+                *undetermined.entry(barcode).or_insert(0) += count;
+            }
+        }
+
+        stats.undetermined = undetermined
+            .into_iter()
+            .map(|(barcode, count)| (barcode.to_string(), count))
+            .collect();
+        stats.finish();
+        stats.lanes = lanes;
+        stats
+    }
+
+    /// Reads per sample, as a table on stdout
+    pub fn print_samples(&self) {
+        let width = self.samples.iter().map(|x| x.id.len()).max().unwrap_or(0);
+
+        for sample in self.samples.iter() {
+            println!(
+                "{:width$}  {:>12}  {:>6.2}%  {:>6.2}% perfect",
+                sample.id,
+                sample.reads,
+                sample.percent_of_total,
+                percent(sample.perfect_index_reads, sample.reads),
+                width = width
+            );
+        }
+    }
+
+    pub fn print_summary(&self) {
+        println!(
+            "Assigned: {} ({:.2}%) Ambiguous: {} ({:.2}%) Unassigned: {} ({:.2}%)",
+            self.assigned_reads,
+            percent(self.assigned_reads, self.total_reads),
+            self.ambiguous_reads,
+            percent(self.ambiguous_reads, self.total_reads),
+            self.unassigned_reads,
+            percent(self.unassigned_reads, self.total_reads)
+        );
+
+        if self.index_hopping.is_some() {
+            println!(
+                "Hopped: {} ({:.2}% of assigned and hopped reads)",
+                self.hopped_reads,
+                self.hopping_rate * 100.0
+            );
+        }
+    }
+
+    /// Demultiplex_Stats.csv in the style of bcl2fastq / BCL Convert. Fractions are 0 to 1,
+    /// AMBIGUOUS, HOPPED and UNASSIGNED are listed after the samples, one set of rows per lane.
+    /// % Reads is of all reads, HOPPED too: the hopping rate in stats.json is of assigned plus
+    /// hopped reads instead.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from(
+            "Lane,SampleID,Index,# Reads,# Perfect Index Reads,# Mismatched Index Reads,\
+             % Reads,% Perfect Index Reads,% Mismatched Index Reads\n",
+        );
+
+        match self.lanes.is_empty() {
+            true => self.csv_rows(&mut csv),
+            false => self.lanes.iter().for_each(|x| x.csv_rows(&mut csv)),
+        }
+
+        csv
+    }
+
+    fn csv_rows(&self, csv: &mut String) {
+        let fraction = |n: usize, total: usize| n as f64 / total.max(1) as f64;
+        let lane = self.lane.map_or(String::new(), |x| x.to_string());
+
+        for sample in self.samples.iter() {
+            csv.push_str(&format!(
+                "{},{},{},{},{},{},{:.4},{:.4},{:.4}\n",
+                lane,
+                sample.id,
+                sample.index,
+                sample.reads,
+                sample.perfect_index_reads,
+                sample.mismatched_index_reads,
+                fraction(sample.reads, self.total_reads),
+                fraction(sample.perfect_index_reads, sample.reads),
+                fraction(sample.mismatched_index_reads, sample.reads)
+            ));
+        }
+
+        for (id, reads) in [
+            (AMBIGUOUS, self.ambiguous_reads),
+            (HOPPED, self.hopped_reads),
+            (UNASSIGNED, self.unassigned_reads),
+        ] {
+            csv.push_str(&format!(
+                "{},{},,{},0,0,{:.4},0.0000,0.0000\n",
+                lane,
+                id,
+                reads,
+                fraction(reads, self.total_reads)
+            ));
+        }
+    }
+
+    /// The `top` most common undetermined barcodes, each compared against every sample
+    pub fn top_undetermined(
+        &self,
+        samples: &SampleSheet,
+        mode: MatchMode,
+        mismatches: (u32, u32),
+        top: usize,
+    ) -> Vec<UndeterminedBarcode> {
+        let dual = samples.iter().any(|x| x.i5.is_some());
+
+        self.undetermined
+            .iter()
+            .take(top)
+            .map(|(barcode, reads)| {
+                let mut read_barcodes = barcode.split('+');
+                let read_i7 = read_barcodes.next().unwrap_or("");
+                let read_i5 = read_barcodes.next().unwrap_or("");
+
+                let distances: Vec<(&str, u32, u32)> = samples
+                    .iter()
+                    .map(|x| {
+                        let i7 = index_distance(mode, read_i7.as_bytes(), x.i7.as_bytes());
+                        let i5 = match dual {
+                            true => index_distance(
+                                mode,
+                                read_i5.as_bytes(),
+                                x.i5.as_deref().unwrap_or("").as_bytes(),
+                            ),
+                            false => 0,
+                        };
+                        (x.id.as_str(), i7, i5)
+                    })
+                    .collect();
+
+                let distance = distances.iter().map(|x| x.1 + x.2).min().unwrap_or(0);
+                let mut nearest: Vec<String> = Vec::new();
+                for (id, i7, i5) in distances.iter() {
+                    if i7 + i5 == distance && !nearest.iter().any(|x| x == id) {
+                        nearest.push(id.to_string());
+                    }
+                }
+
+                UndeterminedBarcode {
+                    barcode: barcode.clone(),
+                    reads: *reads,
+                    nearest,
+                    distance,
+                    i7_match: distances.iter().any(|x| x.1 <= mismatches.0),
+                    i5_match: match dual {
+                        true => Some(distances.iter().any(|x| x.2 <= mismatches.1)),
+                        false => None,
+                    },
+                }
+            })
+            .collect()
+    }
+
+    /// Writes stats.json and Demultiplex_Stats.csv to `output_directory`
+    pub fn write(&self, output_directory: &str) -> Result<(), DeezmuxError> {
+        let directory = Path::new(output_directory);
+
+        let path = directory.join("stats.json");
+        let json = serde_json::to_string_pretty(self).expect("Stats are always serializable");
+        fs::write(&path, json + "\n").map_err(DeezmuxError::io(format!(
+            "Unable to write {}",
+            path.display()
+        )))?;
+
+        let path = directory.join("Demultiplex_Stats.csv");
+        fs::write(&path, self.to_csv()).map_err(DeezmuxError::io(format!(
+            "Unable to write {}",
+            path.display()
+        )))?;
+
+        Ok(())
+    }
+}
+
+/// undetermined_barcodes.tsv, ranked by read count
+pub fn undetermined_tsv(barcodes: &[UndeterminedBarcode]) -> String {
+    let yes_no = |x: bool| match x {
+        true => "yes",
+        false => "no",
+    };
+
+    let mut tsv = String::from("barcode\treads\tnearest_sample\tdistance\ti7_match\ti5_match\n");
+    for x in barcodes.iter() {
+        tsv.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\n",
+            x.barcode,
+            x.reads,
+            x.nearest.join(","),
+            x.distance,
+            yes_no(x.i7_match),
+            x.i5_match.map_or("NA", yes_no)
+        ));
+    }
+
+    tsv
+}
+
+pub fn write_undetermined(
+    output_directory: &str,
+    barcodes: &[UndeterminedBarcode],
+) -> Result<(), DeezmuxError> {
+    let path = Path::new(output_directory).join("undetermined_barcodes.tsv");
+    fs::write(&path, undetermined_tsv(barcodes)).map_err(DeezmuxError::io(format!(
+        "Unable to write {}",
+        path.display()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_perfect_and_mismatched() {
+        let samples: SampleSheet = vec![
+            Sample::new("S1", "AAAAAAAA", Some("CCCCCCCC")),
+            Sample::new("S2", "GGGGGGGG", Some("TTTTTTTT")),
+        ]
+        .into_iter()
+        .collect();
+
+        let matcher = BarcodeMatcher::new(MatchMode::Hamming, samples.clone(), (1, 1));
+        let stats = DemuxStats::new(
+            &matcher,
+            vec![
+                ("AAAAAAAA+CCCCCCCC", "S1", 6),
+                ("AAAAAAAT+CCCCCCCC", "S1", 2),
+                ("ACACACAC+CCCCCCCC", UNASSIGNED, 1),
+                ("AAAAAAAG+TTTTTTTT", AMBIGUOUS, 1),
+            ],
+        );
+
+        assert_eq!(stats.total_reads, 10);
+        assert_eq!(stats.assigned_reads, 8);
+        assert_eq!(stats.ambiguous_reads, 1);
+        assert_eq!(stats.unassigned_reads, 1);
+        assert_eq!(
+            stats.samples[0],
+            SampleStats {
+                id: "S1".to_string(),
+                index: "AAAAAAAA-CCCCCCCC".to_string(),
+                reads: 8,
+                perfect_index_reads: 6,
+                mismatched_index_reads: 2,
+                percent_of_total: 80.0,
+            }
+        );
+        assert_eq!(stats.samples[1].reads, 0);
+
+        let csv = stats.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[1], ",S1,AAAAAAAA-CCCCCCCC,8,6,2,0.8000,0.7500,0.2500");
+        assert_eq!(lines[5], ",UNASSIGNED,,1,0,0,0.1000,0.0000,0.0000");
+
+        let top = stats.top_undetermined(&samples, MatchMode::Hamming, (1, 1), 1);
+        assert_eq!(
+            top,
+            vec![UndeterminedBarcode {
+                barcode: "AAAAAAAG+TTTTTTTT".to_string(),
+                reads: 1,
+                nearest: vec!["S2".to_string()],
+                distance: 7,
+                i7_match: true,
+                i5_match: Some(true),
+            }]
+        );
+    }
+
+    #[test]
+    fn undetermined_barcodes_tsv() {
+        let samples: SampleSheet = vec![
+            Sample::new("S1", "AAAAAAAA", Some("CCCCCCCC")),
+            Sample::new("S2", "GGGGGGGG", Some("TTTTTTTT")),
+        ]
+        .into_iter()
+        .collect();
+
+        let matcher = BarcodeMatcher::new(MatchMode::Hamming, samples.clone(), (1, 1));
+        let stats = DemuxStats::new(
+            &matcher,
+            vec![
+                ("AAAAAAAA+CCCCCCCC", "S1", 10),
+                ("TTTTTTTT+TTTTTTTT", UNASSIGNED, 1),
+                ("CCCCCCCC+CCCCCCCC", UNASSIGNED, 3),
+                ("AAAAAAAA+GGGGGGGG", UNASSIGNED, 5),
+                ("AAAAGGGG+CCCCTTTT", UNASSIGNED, 3),
+            ],
+        );
+
+        // Most reads first, then by barcode, cut off after the top 3
+        let top = stats.top_undetermined(&samples, MatchMode::Hamming, (1, 1), 3);
+        let directory = std::env::temp_dir().join("deezmux_undetermined_barcodes_tsv");
+        fs::create_dir_all(&directory).unwrap();
+        write_undetermined(directory.to_str().unwrap(), &top).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(directory.join("undetermined_barcodes.tsv")).unwrap(),
+            "barcode\treads\tnearest_sample\tdistance\ti7_match\ti5_match\n\
+             AAAAAAAA+GGGGGGGG\t5\tS1\t8\tyes\tno\n\
+             AAAAGGGG+CCCCTTTT\t3\tS1,S2\t8\tno\tno\n\
+             CCCCCCCC+CCCCCCCC\t3\tS1\t8\tno\tyes\n"
+        );
+        fs::remove_dir_all(&directory).unwrap();
+
+        // Single-index sheets have no i5 to match
+        let samples: SampleSheet = vec![Sample::new("S1", "AAAAAAAA", None)]
+            .into_iter()
+            .collect();
+        let matcher = BarcodeMatcher::new(MatchMode::Hamming, samples.clone(), (1, 1));
+        let stats = DemuxStats::new(&matcher, vec![("AAAAAATT", UNASSIGNED, 2)]);
+        let top = stats.top_undetermined(&samples, MatchMode::Hamming, (1, 1), 10);
+        assert_eq!(
+            undetermined_tsv(&top).lines().nth(1),
+            Some("AAAAAATT\t2\tS1\t2\tno\tNA")
+        );
+    }
+
+    #[test]
+    fn counts_index_hopping() {
+        let samples: SampleSheet = vec![
+            Sample::new("S1", "AAAAAAAA", Some("CCCCCCCC")),
+            Sample::new("S2", "GGGGGGGG", Some("TTTTTTTT")),
+        ]
+        .into_iter()
+        .collect();
+
+        let matcher = BarcodeMatcher::new(MatchMode::Hamming, samples, (1, 1));
+        let barcodes = [
+            "AAAAAAAA+CCCCCCCC",
+            "GGGGGGGG+TTTTTTTT",
+            "AAAAAAAA+TTTTTTTT",
+        ];
+        let stats = DemuxStats::new(
+            &matcher,
+            barcodes
+                .iter()
+                .zip([6, 2, 2])
+                .map(|(barcode, count)| (*barcode, matcher.assign(barcode), count)),
+        );
+
+        assert_eq!(stats.hopped_reads, 2);
+        assert_eq!(stats.hopping_rate, 0.2);
+        assert_eq!(
+            stats.index_hopping.unwrap().counts,
+            vec![vec![6, 2], vec![0, 2]]
+        );
+    }
+
+    #[test]
+    fn merges_lanes() {
+        let samples: SampleSheet = vec![
+            Sample::new("S1", "AAAAAAAA", Some("CCCCCCCC")),
+            Sample::new("S2", "GGGGGGGG", Some("TTTTTTTT")),
+        ]
+        .into_iter()
+        .collect();
+
+        let lane = |lane: u32, samples: SampleSheet, barcodes: &[(&str, usize)]| {
+            let matcher = BarcodeMatcher::new(MatchMode::Hamming, samples, (1, 1));
+            let mut stats = DemuxStats::new(
+                &matcher,
+                barcodes
+                    .iter()
+                    .map(|(barcode, count)| (*barcode, matcher.assign(barcode), *count)),
+            );
+            stats.lane = Some(lane);
+            stats
+        };
+
+        let stats = DemuxStats::merge_lanes(vec![
+            lane(
+                1,
+                samples.clone(),
+                &[("AAAAAAAA+CCCCCCCC", 3), ("ACACACAC+ACACACAC", 1)],
+            ),
+            lane(
+                2,
+                samples.iter().skip(1).cloned().collect(),
+                &[("GGGGGGGG+TTTTTTTT", 4), ("ACACACAC+ACACACAC", 2)],
+            ),
+        ]);
+
+        assert_eq!(stats.total_reads, 10);
+        assert_eq!(stats.samples[0].reads, 3);
+        assert_eq!(stats.samples[1].reads, 4);
+        assert_eq!(stats.samples[1].percent_of_total, 40.0);
+        assert_eq!(
+            stats.undetermined,
+            vec![("ACACACAC+ACACACAC".to_string(), 3)]
+        );
+        assert_eq!(
+            stats.index_hopping.as_ref().unwrap().counts,
+            vec![vec![3, 0], vec![0, 4]]
+        );
+
+        let csv = stats.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines[1],
+            "1,S1,AAAAAAAA-CCCCCCCC,3,3,0,0.7500,1.0000,0.0000"
+        );
+        assert_eq!(
+            lines[6],
+            "2,S2,GGGGGGGG-TTTTTTTT,4,4,0,0.6667,1.0000,0.0000"
+        );
+    }
+}
@@ -0,0 +1,164 @@
+use crate::matcher::*;
+use crate::samplesheet::*;
+
+/// Two samples whose barcodes are close enough that a read could match both
+#[derive(Debug, PartialEq)]
+pub struct Conflict {
+    pub sample_a: String,
+    pub sample_b: String,
+    pub i7_distance: u32,
+    pub i5_distance: u32,
+}
+
+pub struct Validation {
+    pub conflicts: Vec<Conflict>,
+    /// Largest mismatch count per index that keeps every distinct barcode apart,
+    /// None when an index only has a single barcode
+    pub max_safe_mismatches: (Option<u32>, Option<u32>),
+}
+
+impl Validation {
+    pub fn is_ok(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+
+    pub fn report(&self) {
+        for c in self.conflicts.iter() {
+            eprintln!(
+                "Conflict: {} and {} (i7 distance {}, i5 distance {})",
+                c.sample_a, c.sample_b, c.i7_distance, c.i5_distance
+            );
+        }
+
+        let safe = |x: Option<u32>| match x {
+            Some(x) => x.to_string(),
+            None => "any".to_string(),
+        };
+
+        eprintln!(
+            "Maximum safe mismatches: i7 {} i5 {}",
+            safe(self.max_safe_mismatches.0),
+            safe(self.max_safe_mismatches.1)
+        );
+    }
+}
+
+/// Largest mismatch count where the neighborhoods of the two closest barcodes do not overlap
+fn max_safe(mode: MatchMode, barcodes: &[&str]) -> Option<u32> {
+    let mut min_distance = None;
+
+    for (i, a) in barcodes.iter().enumerate() {
+        for b in barcodes[i + 1..].iter().filter(|b| b != &a) {
+            let d = index_distance(mode, a.as_bytes(), b.as_bytes());
+            min_distance = Some(min_distance.map_or(d, |x: u32| x.min(d)));
+        }
+    }
+
+    min_distance.map(|d| d.saturating_sub(1) / 2)
+}
+
+/// Computes pairwise i7 and i5 distances for every sample. A pair conflicts when both of
+/// its indexes are within twice the allowed mismatches, so one read could be in range of both
+pub fn validate_barcodes(
+    samples: &SampleSheet,
+    mode: MatchMode,
+    mismatches: (u32, u32),
+) -> Validation {
+    let mut conflicts = Vec::new();
+
+    for (i, a) in samples.iter().enumerate() {
+        for b in samples.iter().skip(i + 1) {
+            // Samples in different lanes never compete for the same reads
+            let share_lane = a.lanes.is_empty()
+                || b.lanes.is_empty()
+                || a.lanes.iter().any(|x| b.lanes.contains(x));
+            if !share_lane {
+                continue;
+            }
+
+            let i7_distance = index_distance(mode, a.i7.as_bytes(), b.i7.as_bytes());
+            let i5_distance = index_distance(
+                mode,
+                a.i5.as_deref().unwrap_or("").as_bytes(),
+                b.i5.as_deref().unwrap_or("").as_bytes(),
+            );
+
+            if i7_distance <= 2 * mismatches.0 && i5_distance <= 2 * mismatches.1 {
+                conflicts.push(Conflict {
+                    sample_a: a.id.clone(),
+                    sample_b: b.id.clone(),
+                    i7_distance,
+                    i5_distance,
+                });
+            }
+        }
+    }
+
+    let i7: Vec<&str> = samples.iter().map(|x| x.i7.as_str()).collect();
+    let i5: Vec<&str> = samples.iter().filter_map(|x| x.i5.as_deref()).collect();
+
+    Validation {
+        conflicts,
+        max_safe_mismatches: (max_safe(mode, &i7), max_safe(mode, &i5)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(i7: &str, i5: &str, id: &str) -> Sample {
+        Sample::new(id, i7, Some(i5))
+    }
+
+    #[test]
+    fn finds_conflicts() {
+        let samples: SampleSheet = vec![
+            sample("AAAAAAAA", "CCCCCCCC", "S1"),
+            sample("AAAAAATT", "CCCCCCCA", "S2"),
+            sample("GGGGGGGG", "TTTTTTTT", "S3"),
+        ]
+        .into_iter()
+        .collect();
+
+        let validation = validate_barcodes(&samples, MatchMode::Hamming, (1, 1));
+        assert_eq!(
+            validation.conflicts,
+            vec![Conflict {
+                sample_a: "S1".to_string(),
+                sample_b: "S2".to_string(),
+                i7_distance: 2,
+                i5_distance: 1,
+            }]
+        );
+        assert_eq!(validation.max_safe_mismatches, (Some(0), Some(0)));
+
+        let validation = validate_barcodes(&samples, MatchMode::Hamming, (0, 1));
+        assert!(validation.is_ok());
+    }
+
+    #[test]
+    fn shared_index_is_not_a_conflict_by_itself() {
+        let samples: SampleSheet = vec![
+            sample("AAAAAAAA", "CCCCCCCC", "S1"),
+            sample("AAAAAAAA", "GGGGGGGG", "S2"),
+        ]
+        .into_iter()
+        .collect();
+
+        let validation = validate_barcodes(&samples, MatchMode::Hamming, (1, 1));
+        assert!(validation.is_ok());
+        assert_eq!(validation.max_safe_mismatches, (None, Some(3)));
+    }
+
+    #[test]
+    fn same_barcodes_in_different_lanes() {
+        let mut a = sample("AAAAAAAA", "CCCCCCCC", "S1");
+        let mut b = sample("AAAAAAAA", "CCCCCCCC", "S2");
+        a.lanes = vec![1];
+        b.lanes = vec![2];
+
+        let samples: SampleSheet = vec![a, b].into_iter().collect();
+        assert!(validate_barcodes(&samples, MatchMode::Hamming, (1, 1)).is_ok());
+    }
+}